@@ -0,0 +1,17 @@
+-- Record of every mutating operation
+CREATE TABLE audit_log (
+    id          bigint GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    created_at  timestamptz NOT NULL DEFAULT now(),
+    actor       varchar(256) NOT NULL,
+    route       varchar(512) NOT NULL,
+    action      varchar(32) NOT NULL,
+    entity      varchar(64) NOT NULL,
+    year        integer,
+    participant varchar(256),
+    keys        jsonb NOT NULL,
+    before      jsonb,
+    after       jsonb
+);
+
+CREATE INDEX audit_log_created_at_idx ON audit_log (created_at);
+CREATE INDEX audit_log_participant_idx ON audit_log (participant);
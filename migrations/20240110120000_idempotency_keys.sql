@@ -0,0 +1,15 @@
+-- Responses to requests made with an Idempotency-Key header, replayed when the
+-- same key is used again
+CREATE TABLE idempotency_keys (
+    key          varchar(256) NOT NULL,
+    route        varchar(512) NOT NULL,
+    request_hash char(64) NOT NULL,
+    created_at   timestamptz NOT NULL DEFAULT now(),
+    -- null while the original request is still being handled
+    status       smallint,
+    content_type varchar(256),
+    body         bytea,
+    PRIMARY KEY (key, route)
+);
+
+CREATE INDEX idempotency_keys_created_at_idx ON idempotency_keys (created_at);
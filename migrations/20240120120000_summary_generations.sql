@@ -0,0 +1,12 @@
+-- When the summaries of each year were last generated, whether or not that
+-- changed any of them
+CREATE TABLE summary_generations (
+    year         integer PRIMARY KEY,
+    generated_at timestamptz NOT NULL
+);
+
+-- the audit log is the best we have to go on for earlier generations
+INSERT INTO summary_generations (year, generated_at)
+SELECT year, max(created_at) FROM audit_log
+WHERE entity = 'summaries' AND year IS NOT NULL
+GROUP BY year;
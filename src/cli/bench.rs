@@ -0,0 +1,306 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+
+use super::submit::submit;
+use crate::{
+    client::{ClientConfig, DEFAULT_CLIENT_CONFIG},
+    models::BenchmarkCreate,
+};
+
+/// Replaced by the path of each input in the command's arguments.
+const INPUT_PLACEHOLDER: &str = "{input}";
+
+#[derive(Debug, Clone, Args)]
+pub struct Bench {
+    /// The inputs to run the solution on, named after their file stem.
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// The solution to run and its arguments, after `--`, where `{input}` is
+    /// replaced by the path of each input. Without it, the input is passed on
+    /// stdin instead. The command is run directly, not through a shell.
+    #[arg(last = true, required = true, value_name = "COMMAND")]
+    command: Vec<String>,
+
+    #[arg(long)]
+    year: i32,
+
+    #[arg(long)]
+    day: i32,
+
+    /// How many times each input is measured.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    runs: u32,
+
+    /// How many times each input is run before measuring.
+    #[arg(long, default_value_t = 3)]
+    warmup: u32,
+
+    /// Submit the results to the server in the client config.
+    #[arg(long)]
+    submit: bool,
+
+    /// The local config with the server, participant and language.
+    #[arg(long, default_value = DEFAULT_CLIENT_CONFIG)]
+    client_config: PathBuf,
+}
+
+impl Bench {
+    pub async fn run(&self) -> anyhow::Result<()> {
+        // fail before spending time on measurements that can't be submitted
+        let config = self
+            .submit
+            .then(|| ClientConfig::load(&self.client_config))
+            .transpose()?;
+
+        let bench = self.clone();
+        let mut data = tokio::task::spawn_blocking(move || bench.measure_all()).await??;
+
+        if let Some(config) = config {
+            for benchmark in &mut data {
+                benchmark.participant = config.participant.clone();
+                benchmark.language = config.language.clone();
+            }
+
+            submit(&config, data, false).await?;
+        }
+
+        Ok(())
+    }
+
+    fn measure_all(&self) -> anyhow::Result<Vec<BenchmarkCreate>> {
+        let mut data = Vec::new();
+
+        for input in &self.inputs {
+            let name = input
+                .file_stem()
+                .ok_or_else(|| anyhow!("{} is not a file", input.display()))?
+                .to_string_lossy()
+                .into_owned();
+
+            for _ in 0..self.warmup {
+                self.run_once(input)?;
+            }
+
+            let runs = (0..self.runs)
+                .map(|_| self.run_once(input))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            let stats = statistics(&runs);
+            println!(
+                "{name}: {:.6}s ± {:.6}s (median {:.6}s, range {:.6}s … {:.6}s, {} runs)",
+                stats.mean, stats.stddev, stats.median, stats.min, stats.max, self.runs
+            );
+
+            data.push(BenchmarkCreate {
+                year: self.year,
+                day: self.day,
+                input: name,
+                ..stats
+            });
+        }
+
+        Ok(data)
+    }
+
+    fn run_once(&self, input: &Path) -> anyhow::Result<Run> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow!("the command is empty"))?;
+        let path = input.to_string_lossy();
+
+        let mut command = Command::new(program);
+        command
+            .args(args.iter().map(|arg| arg.replace(INPUT_PLACEHOLDER, &path)))
+            .stdout(Stdio::null());
+
+        if args.iter().any(|arg| arg.contains(INPUT_PLACEHOLDER)) {
+            command.stdin(Stdio::null());
+        } else {
+            let file =
+                File::open(input).with_context(|| format!("could not read {}", input.display()))?;
+            command.stdin(file);
+        }
+
+        let (user, system) = child_cpu_times();
+        let start = Instant::now();
+        let status = command
+            .status()
+            .with_context(|| format!("could not run `{}`", self.command.join(" ")))?;
+        let wall = start.elapsed().as_secs_f64();
+        let (user_after, system_after) = child_cpu_times();
+
+        if !status.success() {
+            bail!(
+                "`{}` failed on {} with {status}",
+                self.command.join(" "),
+                input.display()
+            );
+        }
+
+        Ok(Run {
+            wall,
+            user: user_after - user,
+            system: system_after - system,
+        })
+    }
+}
+
+/// The time taken by a single run, in seconds.
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    wall: f64,
+    user: f64,
+    system: f64,
+}
+
+/// Summarize the runs of a single input the same way hyperfine does, leaving
+/// the identifying fields empty.
+fn statistics(runs: &[Run]) -> BenchmarkCreate {
+    let n = runs.len() as f64;
+    let mean = |f: fn(&Run) -> f64| runs.iter().map(f).sum::<f64>() / n;
+
+    let mut times: Vec<f64> = runs.iter().map(|r| r.wall).collect();
+    times.sort_by(f64::total_cmp);
+
+    let wall = mean(|r| r.wall);
+
+    // the sample standard deviation, which is undefined for a single run
+    let stddev = if runs.len() > 1 {
+        (times.iter().map(|t| (t - wall).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+    } else {
+        0.0
+    };
+
+    let mid = times.len() / 2;
+    let median = if times.len() % 2 == 0 {
+        (times[mid - 1] + times[mid]) / 2.0
+    } else {
+        times[mid]
+    };
+
+    BenchmarkCreate {
+        mean: wall,
+        stddev,
+        median,
+        user: mean(|r| r.user),
+        system: mean(|r| r.system),
+        min: times[0],
+        max: times[times.len() - 1],
+        ..Default::default()
+    }
+}
+
+/// The user and system time used by every child that has been waited on.
+#[cfg(unix)]
+fn child_cpu_times() -> (f64, f64) {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+
+    // SAFETY: getrusage only writes to the struct it is given, which was
+    // zeroed to begin with
+    let usage = unsafe {
+        libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr());
+        usage.assume_init()
+    };
+
+    let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1e6;
+
+    (seconds(usage.ru_utime), seconds(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn child_cpu_times() -> (f64, f64) {
+    (0.0, 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(wall: f64) -> Run {
+        Run {
+            wall,
+            user: wall / 2.0,
+            system: wall / 4.0,
+        }
+    }
+
+    #[test]
+    fn statistics_match_hyperfine() {
+        let stats = statistics(&[run(4.0), run(1.0), run(3.0), run(2.0)]);
+
+        assert_eq!(stats.mean, 2.5);
+        assert!((stats.stddev - 1.290_994).abs() < 1e-6);
+        assert_eq!(stats.median, 2.5);
+        assert_eq!(stats.user, 1.25);
+        assert_eq!(stats.system, 0.625);
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 4.0);
+
+        let stats = statistics(&[run(1.0)]);
+        assert_eq!(stats.stddev, 0.0);
+        assert_eq!(stats.median, 1.0);
+    }
+
+    #[test]
+    fn command_follows_inputs() {
+        #[derive(Debug, clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            bench: Bench,
+        }
+
+        let cli = <Cli as clap::Parser>::try_parse_from([
+            "bench", "--year", "2023", "--day", "1", "a.txt", "b.txt", "--", "./solve", "--fast",
+            "{input}",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.bench.inputs, [PathBuf::from("a.txt"), "b.txt".into()]);
+        assert_eq!(cli.bench.command, ["./solve", "--fast", "{input}"]);
+    }
+
+    #[test]
+    fn measures_each_input() -> anyhow::Result<()> {
+        let input = std::env::temp_dir().join(format!("aoc-web-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&input, "1 2 3\n")?;
+
+        // the test binary itself is the one program we know is there, and it
+        // only lists the tests when asked to
+        let program = std::env::current_exe()?.to_string_lossy().into_owned();
+
+        let bench = Bench {
+            command: vec![program.clone(), "--list".into()],
+            inputs: vec![input.clone()],
+            year: 2023,
+            day: 1,
+            runs: 2,
+            warmup: 1,
+            submit: false,
+            client_config: DEFAULT_CLIENT_CONFIG.into(),
+        };
+
+        let data = bench.measure_all()?;
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].input, input.file_stem().unwrap().to_string_lossy());
+        assert!(data[0].min <= data[0].max);
+
+        let failing = Bench {
+            command: vec![program, "--no-such-flag".into(), "{input}".into()],
+            ..bench
+        };
+        assert!(failing.measure_all().is_err());
+
+        std::fs::remove_file(input)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use clap::Subcommand;
+use time::format_description::well_known::Rfc3339;
+
+use crate::{
+    config::Config,
+    models::{MigrationState, MigrationStatus, ModelManager},
+};
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Migrate {
+    /// Apply all pending migrations.
+    Up,
+
+    /// List applied and pending migrations.
+    Status,
+
+    /// Show when and how long each applied migration took.
+    Info,
+}
+
+impl Migrate {
+    pub async fn run(&self, config: &Config) -> anyhow::Result<()> {
+        let mm = ModelManager::new(&config.db).await?;
+
+        match self {
+            Migrate::Up => {
+                let pending = count_pending(&mm.migration_status().await?);
+                mm.migrate().await?;
+
+                if pending == 0 {
+                    println!("database is up to date");
+                } else {
+                    println!("applied {pending} migration(s)");
+                }
+            }
+            Migrate::Status => {
+                let statuses = mm.migration_status().await?;
+
+                for s in &statuses {
+                    println!("{:<18} {} {}", s.state.as_ref(), s.version, s.description);
+                }
+
+                println!(
+                    "\n{} applied, {} pending",
+                    statuses.len() - count_pending(&statuses),
+                    count_pending(&statuses)
+                );
+            }
+            Migrate::Info => {
+                for s in mm.migration_status().await? {
+                    println!("{} {}", s.version, s.description);
+                    println!("  state:          {}", s.state.as_ref());
+
+                    if let Some(installed_on) = s.installed_on {
+                        println!("  installed on:   {}", installed_on.format(&Rfc3339)?);
+                    }
+
+                    if let Some(execution_time) = s.execution_time {
+                        println!("  execution time: {:?}", execution_time);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn count_pending(statuses: &[MigrationStatus]) -> usize {
+    statuses
+        .iter()
+        .filter(|s| s.state == MigrationState::Pending)
+        .count()
+}
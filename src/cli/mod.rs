@@ -0,0 +1,99 @@
+mod bench;
+mod config;
+mod migrate;
+mod submit;
+mod summaries;
+mod transfer;
+
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{config::Config, server};
+
+use self::{
+    bench::Bench,
+    config::ConfigCommand,
+    migrate::Migrate,
+    submit::Submit,
+    summaries::Summaries,
+    transfer::{Export, Import},
+};
+
+/// Changes made from the command line are attributed to this actor.
+const CLI_ACTOR: &str = "cli";
+
+#[derive(Debug, Parser)]
+#[command(version, max_term_width = 120)]
+pub struct Cli {
+    /// A TOML file to load settings from. `AOC_` env vars take precedence over
+    /// anything in it.
+    #[arg(long, global = true, env = "AOC_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+impl Cli {
+    pub async fn run() -> anyhow::Result<()> {
+        let cli = Self::parse();
+
+        cli.command.run(cli.config.as_deref()).await
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    Server(Server),
+
+    /// Inspect the server config.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Manage database migrations.
+    #[command(subcommand)]
+    Migrate(Migrate),
+
+    /// Manage summaries without going through the API.
+    #[command(subcommand)]
+    Summaries(Summaries),
+
+    /// Dump participants, benchmarks and summaries to a directory.
+    Export(Export),
+
+    /// Load participants, benchmarks and summaries from an export.
+    Import(Import),
+
+    /// Submit hyperfine results to a remote server.
+    Submit(Submit),
+
+    /// Measure a solution locally, optionally submitting the results.
+    Bench(Bench),
+}
+
+impl Command {
+    // the client commands don't need the server config, so it is only loaded
+    // for the commands that do
+    async fn run(&self, config: Option<&Path>) -> anyhow::Result<()> {
+        match self {
+            Command::Server(cmd) => cmd.run(Config::load(config)?).await,
+            Command::Config(cmd) => cmd.run(config).await,
+            Command::Migrate(cmd) => cmd.run(&Config::load(config)?).await,
+            Command::Summaries(cmd) => cmd.run(&Config::load(config)?).await,
+            Command::Export(cmd) => cmd.run(&Config::load(config)?).await,
+            Command::Import(cmd) => cmd.run(&Config::load(config)?).await,
+            Command::Submit(cmd) => cmd.run().await,
+            Command::Bench(cmd) => cmd.run().await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Server {}
+
+impl Server {
+    async fn run(&self, config: Config) -> anyhow::Result<()> {
+        server::serve(config).await
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Args;
+use serde::Deserialize;
+
+use crate::{
+    client::{Client, ClientConfig, PathPatterns, DEFAULT_CLIENT_CONFIG},
+    models::BenchmarkCreate,
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct Submit {
+    /// Hyperfine JSON exports, or directories to search for them.
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// The local config with the server, participant and language.
+    #[arg(long, default_value = DEFAULT_CLIENT_CONFIG)]
+    client_config: PathBuf,
+
+    /// Print what would be submitted without sending anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Submit {
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let config = ClientConfig::load(&self.client_config)?;
+        let patterns = PathPatterns::new(&config.patterns)?;
+
+        let mut files = Vec::new();
+        for path in &self.paths {
+            find_json_files(path, &mut files)?;
+        }
+
+        let mut data = Vec::new();
+        for file in files {
+            let (year, day, input) = patterns.infer(&file).ok_or_else(|| {
+                anyhow!(
+                    "could not infer the year, day and input of {}",
+                    file.display()
+                )
+            })?;
+
+            let result = HyperfineResult::from_file(&file)?;
+
+            data.push(BenchmarkCreate {
+                year,
+                day,
+                input,
+                participant: config.participant.clone(),
+                language: config.language.clone(),
+                ..result.into()
+            });
+        }
+
+        submit(&config, data, self.dry_run).await
+    }
+}
+
+/// Send `data` to the configured server, printing the outcome of each record.
+pub async fn submit(
+    config: &ClientConfig,
+    data: Vec<BenchmarkCreate>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    if data.is_empty() {
+        println!("nothing to submit");
+        return Ok(());
+    }
+
+    if dry_run {
+        println!("{}", serde_json::to_string_pretty(&data)?);
+        return Ok(());
+    }
+
+    let client = Client::new(config.server.clone(), config.token.clone());
+    let outcomes = client.submit_benchmarks(&data).await?;
+
+    for (record, outcome) in data.iter().zip(outcomes) {
+        println!(
+            "{:<9} {} day {} {}",
+            outcome["status"].as_str().unwrap_or("unknown"),
+            record.year,
+            record.day,
+            record.input
+        );
+    }
+
+    Ok(())
+}
+
+fn find_json_files(path: &Path, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if path.is_file() {
+        out.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries = fs::read_dir(path)
+        .with_context(|| format!("could not read {}", path.display()))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // keeps the submission order predictable
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            find_json_files(&entry, out)?;
+        } else if entry.extension().is_some_and(|ext| ext == "json") {
+            out.push(entry);
+        }
+    }
+
+    Ok(())
+}
+
+/// The parts of a hyperfine JSON export that we care about.
+#[derive(Debug, Deserialize)]
+struct HyperfineExport {
+    results: Vec<HyperfineResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct HyperfineResult {
+    mean: f64,
+    // hyperfine reports null when there was only a single run
+    stddev: Option<f64>,
+    median: f64,
+    user: f64,
+    system: f64,
+    min: f64,
+    max: f64,
+}
+
+impl HyperfineResult {
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let raw = fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+        let export: HyperfineExport = serde_json::from_slice(&raw)
+            .with_context(|| format!("{} is not a hyperfine export", path.display()))?;
+
+        match <[HyperfineResult; 1]>::try_from(export.results) {
+            Ok([result]) => Ok(result),
+            Err(results) => Err(anyhow!(
+                "expected a single result in {}, found {}",
+                path.display(),
+                results.len()
+            )),
+        }
+    }
+}
+
+impl From<HyperfineResult> for BenchmarkCreate {
+    fn from(value: HyperfineResult) -> Self {
+        Self {
+            mean: value.mean,
+            stddev: value.stddev.unwrap_or_default(),
+            median: value.median,
+            user: value.user,
+            system: value.system,
+            min: value.min,
+            max: value.max,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn reads_hyperfine_exports() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("aoc-web-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("2023/day01"))?;
+
+        let path = dir.join("2023/day01/input-foo.json");
+        fs::write(
+            &path,
+            r#"{"results": [{
+                "command": "target/release/day01",
+                "mean": 0.5, "stddev": null, "median": 0.5,
+                "user": 0.4, "system": 0.1, "min": 0.5, "max": 0.5,
+                "times": [0.5], "exit_codes": [0]
+            }]}"#,
+        )?;
+        fs::write(dir.join("notes.txt"), "not a result")?;
+
+        let mut files = Vec::new();
+        find_json_files(&dir, &mut files)?;
+        assert_eq!(files, vec![path.clone()]);
+
+        let benchmark = BenchmarkCreate::from(HyperfineResult::from_file(&path)?);
+        assert_eq!(benchmark.mean, 0.5);
+        assert_eq!(benchmark.stddev, 0.0);
+
+        fs::write(&path, r#"{"results": []}"#)?;
+        assert!(HyperfineResult::from_file(&path).is_err());
+
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use clap::{Args, Subcommand};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use super::CLI_ACTOR;
+use crate::{
+    config::Config,
+    models::{AuditContext, ModelManager, Summary, SummaryBmc, SummaryFilter},
+};
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Summaries {
+    /// Regenerate summaries from the stored benchmarks.
+    Generate(Generate),
+}
+
+impl Summaries {
+    pub async fn run(&self, config: &Config) -> anyhow::Result<()> {
+        match self {
+            Summaries::Generate(cmd) => cmd.run(config).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Generate {
+    /// The year to generate summaries for.
+    #[arg(long)]
+    year: i32,
+
+    /// Only generate the summary for this participant.
+    #[arg(long)]
+    participant: Option<String>,
+
+    /// Show what would change without writing anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Generate {
+    async fn run(&self, config: &Config) -> anyhow::Result<()> {
+        let mm = ModelManager::new(&config.db).await?;
+
+        let started = OffsetDateTime::now_utc();
+        let mut summaries = SummaryBmc::generate(&mm, self.year, self.participant.clone()).await?;
+
+        if summaries.is_empty() {
+            println!("no benchmarks found for {}", self.year);
+            return Ok(());
+        }
+
+        summaries.sort_by(|a, b| a.participant.cmp(&b.participant));
+
+        if self.dry_run {
+            return self.print_diff(&mm, &summaries).await;
+        }
+
+        let ctx = AuditContext::new(CLI_ACTOR, "summaries generate");
+        let outcomes = SummaryBmc::batch_create_or_update(&mm, &ctx, summaries.clone()).await?;
+
+        // only a whole year brings it up to date
+        if self.participant.is_none() {
+            SummaryBmc::mark_generated(&mm, self.year, started).await?;
+        }
+
+        for outcome in outcomes {
+            let summary = &summaries[outcome.index];
+            println!(
+                "{:<9} {} {}",
+                outcome.status.as_ref(),
+                summary.year,
+                summary.participant
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn print_diff(&self, mm: &ModelManager, summaries: &[Summary]) -> anyhow::Result<()> {
+        let filter = SummaryFilter {
+            year: Some(self.year),
+            participant: self.participant.clone(),
+            ..Default::default()
+        };
+
+        let stored: HashMap<String, Summary> = SummaryBmc::list(mm, filter)
+            .await?
+            .into_iter()
+            .map(|s| (s.participant.clone(), s))
+            .collect();
+
+        for summary in summaries {
+            match stored.get(&summary.participant) {
+                None => println!("create    {} {}", summary.year, summary.participant),
+                Some(existing) => {
+                    let changes = diff(existing, summary)?;
+
+                    if changes.is_empty() {
+                        println!("unchanged {} {}", summary.year, summary.participant);
+                    } else {
+                        println!("update    {} {}", summary.year, summary.participant);
+                        for change in changes {
+                            println!("    {change}");
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Describe every field that differs between two summaries.
+fn diff(old: &Summary, new: &Summary) -> anyhow::Result<Vec<String>> {
+    let Value::Object(old) = serde_json::to_value(old)? else {
+        unreachable!("summaries serialize as objects");
+    };
+    let Value::Object(new) = serde_json::to_value(new)? else {
+        unreachable!("summaries serialize as objects");
+    };
+
+    Ok(new
+        .iter()
+        .filter_map(|(field, value)| {
+            let prev = old.get(field).unwrap_or(&Value::Null);
+            (prev != value).then(|| format!("{field}: {prev} -> {value}"))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_lists_changed_fields() -> anyhow::Result<()> {
+        let old = Summary {
+            year: 2023,
+            participant: "foo".into(),
+            language: "rust".into(),
+            day_1: Some(1.5),
+            total: Some(1.5),
+            ..Default::default()
+        };
+        let new = Summary {
+            day_2: Some(2.0),
+            total: Some(3.5),
+            ..old.clone()
+        };
+
+        assert!(diff(&old, &old)?.is_empty());
+
+        let mut changes = diff(&old, &new)?;
+        changes.sort();
+
+        assert_eq!(changes, vec!["day_2: null -> 2.0", "total: 1.5 -> 3.5"]);
+
+        Ok(())
+    }
+}
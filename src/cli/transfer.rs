@@ -0,0 +1,408 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::CLI_ACTOR;
+use crate::{
+    config::Config,
+    models::{
+        AuditContext, BatchOutcome, BenchmarkBmc, BenchmarkCreate, BenchmarkFilter, ModelManager,
+        OutcomeStatus, Participant, ParticipantBmc, ParticipantFilter, Summary, SummaryBmc,
+        SummaryFilter,
+    },
+};
+
+const PARTICIPANTS: &str = "participants";
+const BENCHMARKS: &str = "benchmarks";
+const SUMMARIES: &str = "summaries";
+
+// keeps each upsert well under the postgres limit on bind parameters
+const IMPORT_CHUNK_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    const ALL: [Format; 3] = [Format::Json, Format::Ndjson, Format::Csv];
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Export {
+    #[arg(long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+
+    /// The directory to write to, which is created if missing.
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Only export this year.
+    #[arg(long)]
+    year: Option<i32>,
+}
+
+impl Export {
+    pub async fn run(&self, config: &Config) -> anyhow::Result<()> {
+        let mm = ModelManager::new(&config.db).await?;
+
+        self.export(&mm).await
+    }
+
+    async fn export(&self, mm: &ModelManager) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.out)
+            .with_context(|| format!("could not create {}", self.out.display()))?;
+
+        let participants = ParticipantBmc::list(
+            mm,
+            ParticipantFilter {
+                year: self.year,
+                ..Default::default()
+            },
+        )
+        .await?;
+        self.write(PARTICIPANTS, &participants)?;
+
+        // ids are local to a database, so they are not exported
+        let benchmarks: Vec<BenchmarkCreate> = BenchmarkBmc::list(
+            mm,
+            BenchmarkFilter {
+                year: self.year,
+                ..Default::default()
+            },
+        )
+        .await?
+        .into_iter()
+        .map(BenchmarkCreate::from)
+        .collect();
+        self.write(BENCHMARKS, &benchmarks)?;
+
+        let summaries = SummaryBmc::list(
+            mm,
+            SummaryFilter {
+                year: self.year,
+                ..Default::default()
+            },
+        )
+        .await?;
+        self.write(SUMMARIES, &summaries)?;
+
+        Ok(())
+    }
+
+    fn write<T: Serialize>(&self, name: &str, records: &[T]) -> anyhow::Result<()> {
+        let path = self.out.join(name).with_extension(self.format.extension());
+
+        write_records(&path, self.format, records)
+            .with_context(|| format!("could not write {}", path.display()))?;
+
+        println!("exported {} {name} to {}", records.len(), path.display());
+
+        Ok(())
+    }
+}
+
+/// Upserts everything from an export.
+///
+/// Records are written in chunks, each in its own transaction, so a failure
+/// partway through leaves the chunks before it in place. Importing only ever
+/// upserts, and records that already match are left alone without an audit
+/// entry, so the import can be run again to finish the job.
+#[derive(Debug, Clone, Args)]
+pub struct Import {
+    /// A directory produced by `export`, in any format.
+    dir: PathBuf,
+
+    /// Only import this year.
+    #[arg(long)]
+    year: Option<i32>,
+}
+
+impl Import {
+    pub async fn run(&self, config: &Config) -> anyhow::Result<()> {
+        let mm = ModelManager::new(&config.db).await?;
+
+        self.import(&mm)
+            .await
+            .context("import stopped partway, it is safe to run it again")
+    }
+
+    // participants go first, as that's the order they would be submitted in
+    async fn import(&self, mm: &ModelManager) -> anyhow::Result<()> {
+        let ctx = AuditContext::new(CLI_ACTOR, "import");
+
+        if let Some(mut participants) = self.read::<Participant>(PARTICIPANTS)? {
+            participants.retain(|p| self.year.map_or(true, |y| p.year == y));
+
+            let mut outcomes = Vec::new();
+            for chunk in participants.chunks(IMPORT_CHUNK_SIZE) {
+                let written =
+                    ParticipantBmc::batch_create_or_update(mm, &ctx, chunk.to_vec()).await?;
+                outcomes.extend(written);
+            }
+            report(PARTICIPANTS, &outcomes);
+        }
+
+        if let Some(mut benchmarks) = self.read::<BenchmarkCreate>(BENCHMARKS)? {
+            benchmarks.retain(|b| self.year.map_or(true, |y| b.year == y));
+
+            let mut outcomes = Vec::new();
+            for chunk in benchmarks.chunks(IMPORT_CHUNK_SIZE) {
+                let written =
+                    BenchmarkBmc::batch_create_or_update(mm, &ctx, chunk.to_vec()).await?;
+                outcomes.extend(written);
+            }
+            report(BENCHMARKS, &outcomes);
+        }
+
+        if let Some(mut summaries) = self.read::<Summary>(SUMMARIES)? {
+            summaries.retain(|s| self.year.map_or(true, |y| s.year == y));
+
+            let mut outcomes = Vec::new();
+            for chunk in summaries.chunks(IMPORT_CHUNK_SIZE) {
+                let written = SummaryBmc::batch_create_or_update(mm, &ctx, chunk.to_vec()).await?;
+                outcomes.extend(written);
+            }
+            report(SUMMARIES, &outcomes);
+        }
+
+        Ok(())
+    }
+
+    /// Read the records for `name` from whichever format is present, if any.
+    fn read<T: DeserializeOwned>(&self, name: &str) -> anyhow::Result<Option<Vec<T>>> {
+        for format in Format::ALL {
+            let path = self.dir.join(name).with_extension(format.extension());
+
+            if path.exists() {
+                let records = read_records(&path, format)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                return Ok(Some(records));
+            }
+        }
+
+        println!("no {name} found in {}", self.dir.display());
+
+        Ok(None)
+    }
+}
+
+fn report<T>(name: &str, outcomes: &[BatchOutcome<T>]) {
+    let count = |status| outcomes.iter().filter(|o| o.status == status).count();
+
+    println!(
+        "imported {} {name}: {} created, {} updated, {} unchanged",
+        outcomes.len(),
+        count(OutcomeStatus::Created),
+        count(OutcomeStatus::Updated),
+        count(OutcomeStatus::Unchanged),
+    );
+}
+
+fn write_records<T: Serialize>(path: &Path, format: Format, records: &[T]) -> anyhow::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    match format {
+        Format::Json => serde_json::to_writer_pretty(&mut out, records)?,
+        Format::Ndjson => {
+            for record in records {
+                serde_json::to_writer(&mut out, record)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut out);
+            for record in records {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+fn read_records<T: DeserializeOwned>(path: &Path, format: Format) -> anyhow::Result<Vec<T>> {
+    let input = BufReader::new(File::open(path)?);
+
+    let records = match format {
+        Format::Json => serde_json::from_reader(input)?,
+        Format::Ndjson => input
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<anyhow::Result<_>>()?,
+        Format::Csv => csv::Reader::from_reader(input)
+            .deserialize()
+            .collect::<Result<_, _>>()?,
+    };
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::models::{AuditBmc, AuditFilter};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("aoc-web-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn records_roundtrip() -> anyhow::Result<()> {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir)?;
+
+        let summaries = vec![
+            Summary {
+                year: 2023,
+                participant: "foo".into(),
+                language: "rust".into(),
+                day_1: Some(1.5),
+                total: Some(1.5),
+                ..Default::default()
+            },
+            Summary {
+                year: 2023,
+                participant: "bar".into(),
+                language: "python".into(),
+                ..Default::default()
+            },
+        ];
+
+        for format in Format::ALL {
+            let path = dir.join(SUMMARIES).with_extension(format.extension());
+
+            write_records(&path, format, &summaries)?;
+            let read: Vec<Summary> = read_records(&path, format)?;
+
+            assert_eq!(read, summaries, "{format:?}");
+        }
+
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn import_validates_summaries(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let dir = temp_dir();
+        fs::create_dir_all(&dir)?;
+
+        let summaries = vec![Summary {
+            year: 2023,
+            participant: "foo".into(),
+            language: "rust".into(),
+            day_3: Some(-1.0),
+            ..Default::default()
+        }];
+        write_records(
+            &dir.join(SUMMARIES).with_extension("json"),
+            Format::Json,
+            &summaries,
+        )?;
+
+        let err = Import {
+            dir: dir.clone(),
+            year: None,
+        }
+        .import(&mm)
+        .await
+        .unwrap_err();
+
+        let Some(crate::models::Error::Validation(errors)) = err.downcast_ref() else {
+            panic!("expected a validation error, got {err:?}");
+        };
+        assert_eq!(errors.0[0].index, 0);
+        assert_eq!(errors.0[0].fields[0].field, "day_3");
+        assert!(SummaryBmc::list(&mm, SummaryFilter::default())
+            .await?
+            .is_empty());
+
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/participants.sql",
+        "../../fixtures/benchmarks.sql",
+        "../../fixtures/summaries.sql"
+    ))]
+    async fn export_then_import(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let dir = temp_dir();
+
+        Export {
+            format: Format::Csv,
+            out: dir.clone(),
+            year: None,
+        }
+        .export(&mm)
+        .await?;
+
+        let benchmarks: Vec<BenchmarkCreate> =
+            read_records(&dir.join(BENCHMARKS).with_extension("csv"), Format::Csv)?;
+        assert_eq!(
+            benchmarks.len(),
+            BenchmarkBmc::list(&mm, BenchmarkFilter::default())
+                .await?
+                .len()
+        );
+
+        // change something so that importing has work to do
+        let mut participants = ParticipantBmc::list(&mm, ParticipantFilter::default()).await?;
+        participants[0].language = "cobol".into();
+        let ctx = AuditContext::new("test", "test");
+        ParticipantBmc::batch_create_or_update(&mm, &ctx, participants.clone()).await?;
+
+        Import {
+            dir: dir.clone(),
+            year: None,
+        }
+        .import(&mm)
+        .await?;
+
+        let restored =
+            ParticipantBmc::get(&mm, participants[0].year, &participants[0].name).await?;
+        assert_ne!(restored.language, "cobol");
+
+        // running it again, as after a partial failure, changes nothing
+        let latest =
+            || async { anyhow::Ok(AuditBmc::list(&mm, AuditFilter::default()).await?[0].id) };
+        let before = latest().await?;
+        Import {
+            dir: dir.clone(),
+            year: None,
+        }
+        .import(&mm)
+        .await?;
+        assert_eq!(latest().await?, before);
+
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, Context};
+use figment::{
+    providers::{Env, Format, Toml},
+    Figment,
+};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+use uuid::Uuid;
+
+use crate::models::BenchmarkCreate;
+
+/// Where the client looks for its config when not told otherwise.
+pub const DEFAULT_CLIENT_CONFIG: &str = "aoc-web-client.toml";
+
+const SUBMIT_ATTEMPTS: u32 = 3;
+
+fn default_patterns() -> Vec<String> {
+    vec![
+        // 2023/day01/input-foo.json
+        r"(?P<year>\d{4})/day[-_]?(?P<day>\d{1,2})/(?P<input>[^/]+)\.json$".into(),
+        // 2023_day01_input-foo.json
+        r"(?P<year>\d{4})[-_]day[-_]?(?P<day>\d{1,2})[-_](?P<input>[^/]+)\.json$".into(),
+    ]
+}
+
+/// Settings for talking to a remote server, read from a local TOML file with
+/// `AOC_CLIENT_` prefixed env vars layered on top.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientConfig {
+    pub server: Url,
+    pub participant: String,
+    pub language: String,
+
+    /// Only needed to submit, so best left to `AOC_CLIENT_TOKEN`.
+    #[serde(default)]
+    pub token: Option<String>,
+
+    /// Regular expressions with `year`, `day` and `input` named groups that
+    /// are matched against the path of each result file, in order.
+    #[serde(default = "default_patterns")]
+    pub patterns: Vec<String>,
+}
+
+impl ClientConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Figment::new()
+            .merge(Toml::file(path))
+            .merge(Env::prefixed("AOC_CLIENT_"))
+            .extract()
+            .with_context(|| format!("could not load client config from {}", path.display()))
+    }
+}
+
+/// Infers the year, day and input of a result file from its path.
+#[derive(Debug, Clone)]
+pub struct PathPatterns(Vec<Regex>);
+
+impl PathPatterns {
+    pub fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                let re = Regex::new(p).with_context(|| format!("invalid pattern {p}"))?;
+
+                for group in ["year", "day", "input"] {
+                    if !re.capture_names().any(|n| n == Some(group)) {
+                        return Err(anyhow!("pattern {p} is missing the `{group}` group"));
+                    }
+                }
+
+                Ok(re)
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self(patterns))
+    }
+
+    /// The year, day and input for `path`, from the first matching pattern.
+    pub fn infer(&self, path: &Path) -> Option<(i32, i32, String)> {
+        // patterns are written with forward slashes regardless of platform
+        let path = path.to_string_lossy().replace('\\', "/");
+
+        self.0.iter().find_map(|re| {
+            let caps = re.captures(&path)?;
+
+            Some((
+                caps["year"].parse().ok()?,
+                caps["day"].parse().ok()?,
+                caps["input"].to_string(),
+            ))
+        })
+    }
+}
+
+/// A minimal client for a remote aoc-web server.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    server: Url,
+    token: Option<String>,
+    retry_delay: Duration,
+}
+
+impl Client {
+    pub fn new(mut server: Url, token: Option<String>) -> Self {
+        // otherwise joining paths would replace the last segment of the base
+        if !server.path().ends_with('/') {
+            let path = format!("{}/", server.path());
+            server.set_path(&path);
+        }
+
+        Self {
+            http: reqwest::Client::new(),
+            server,
+            token,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Submit benchmarks, returning the outcome of each as reported by the
+    /// server.
+    ///
+    /// Failures that may be temporary are retried with the same idempotency
+    /// key, so the server handles the submission at most once.
+    pub async fn submit_benchmarks(&self, data: &[BenchmarkCreate]) -> anyhow::Result<Vec<Value>> {
+        let token = self
+            .token
+            .as_deref()
+            .ok_or_else(|| anyhow!("a token is required to submit benchmarks"))?;
+
+        let url = self.server.join("api/v1/benchmarks")?;
+        let key = Uuid::new_v4().to_string();
+
+        let mut attempt = 1;
+        let response = loop {
+            let response = self
+                .http
+                .post(url.clone())
+                .bearer_auth(token)
+                .header("idempotency-key", &key)
+                .json(data)
+                .send()
+                .await;
+
+            let retry = match &response {
+                Ok(r) => is_retryable(r.status()),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+
+            if !retry || attempt == SUBMIT_ATTEMPTS {
+                break response?;
+            }
+
+            tokio::time::sleep(self.retry_delay * attempt).await;
+            attempt += 1;
+        };
+
+        let status = response.status();
+
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let detail = match serde_json::from_str::<Value>(&body) {
+                Ok(body) => serde_json::to_string_pretty(&body["error"])?,
+                Err(_) => body,
+            };
+
+            return Err(anyhow!("server responded with {status}: {detail}"));
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+// a conflict means the key is still held by an earlier attempt
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    use reqwest::StatusCode;
+
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::CONFLICT
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::post,
+        Json, Router,
+    };
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        models::ModelManager,
+        server::{self, AppState},
+    };
+
+    #[test]
+    fn default_patterns_infer() -> anyhow::Result<()> {
+        let patterns = PathPatterns::new(&default_patterns())?;
+
+        assert_eq!(
+            patterns.infer(&PathBuf::from("results/2023/day01/input-foo.json")),
+            Some((2023, 1, "input-foo".into()))
+        );
+        assert_eq!(
+            patterns.infer(&PathBuf::from("2022_day_15_real.json")),
+            Some((2022, 15, "real".into()))
+        );
+        assert_eq!(patterns.infer(&PathBuf::from("notes.json")), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn patterns_require_groups() {
+        let err = PathPatterns::new(&[r"(?P<year>\d+)/(?P<day>\d+)".into()]).unwrap_err();
+
+        assert!(err.to_string().contains("`input`"));
+    }
+
+    #[test]
+    fn keeps_base_path() -> anyhow::Result<()> {
+        let client = Client::new("http://localhost/aoc".parse()?, None);
+
+        assert_eq!(
+            client.server.join("api/v1/benchmarks")?.as_str(),
+            "http://localhost/aoc/api/v1/benchmarks"
+        );
+
+        Ok(())
+    }
+
+    // fails with `first` until it has seen `failures` requests, recording the
+    // idempotency key of each
+    async fn flaky(
+        failures: usize,
+        first: (StatusCode, &'static str),
+    ) -> anyhow::Result<(Url, Arc<Mutex<Vec<String>>>)> {
+        let keys = Arc::new(Mutex::new(Vec::new()));
+
+        let app = Router::new()
+            .route(
+                "/api/v1/benchmarks",
+                post(move |State(keys): State<Arc<Mutex<Vec<String>>>>, headers: HeaderMap| async move {
+                    let mut keys = keys.lock().unwrap();
+                    keys.push(headers["idempotency-key"].to_str().unwrap().to_string());
+
+                    if keys.len() <= failures {
+                        first.into_response()
+                    } else {
+                        Json(json!([{ "status": "created" }])).into_response()
+                    }
+                }),
+            )
+            .with_state(keys.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Ok((format!("http://{addr}").parse()?, keys))
+    }
+
+    fn client(server: Url) -> Client {
+        Client {
+            retry_delay: Duration::from_millis(10),
+            ..Client::new(server, Some("token".into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_with_the_same_key() -> anyhow::Result<()> {
+        let (server, keys) = flaky(2, (StatusCode::SERVICE_UNAVAILABLE, "down")).await?;
+
+        let outcomes = client(server).submit_benchmarks(&[]).await?;
+        assert_eq!(outcomes[0]["status"], "created");
+
+        let keys = keys.lock().unwrap();
+        assert_eq!(keys.len(), 3);
+        assert!(keys.iter().all(|k| *k == keys[0]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_errors_that_are_not_json() -> anyhow::Result<()> {
+        let (server, keys) = flaky(1, (StatusCode::BAD_REQUEST, "no thanks")).await?;
+
+        let err = client(server).submit_benchmarks(&[]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "server responded with 400 Bad Request: no thanks"
+        );
+
+        // not worth retrying
+        assert_eq!(keys.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn submit_to_server(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let app = server::service(AppState::new(Config::test(), ModelManager::from(pool)))
+            .into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let server: Url = format!("http://{addr}/").parse()?;
+        let data = vec![BenchmarkCreate {
+            year: 2023,
+            day: 1,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            mean: 0.5,
+            stddev: 0.1,
+            median: 0.5,
+            user: 0.4,
+            system: 0.1,
+            min: 0.3,
+            max: 0.7,
+        }];
+
+        let outcomes = Client::new(server.clone(), Some("sandcastle".into()))
+            .submit_benchmarks(&data)
+            .await?;
+        assert_eq!(outcomes[0]["status"], "created");
+
+        let err = Client::new(server, Some("wrong".into()))
+            .submit_benchmarks(&data)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("401"));
+
+        Ok(())
+    }
+}
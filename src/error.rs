@@ -1,4 +1,11 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::models;
 
@@ -12,6 +19,21 @@ pub enum Error {
     #[error("Unauthorized")]
     Unauthorized,
 
+    #[error("Too many requests, retry after {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+
+    #[error("Invalid idempotency key")]
+    InvalidIdempotencyKey,
+
+    #[error("A request with this idempotency key is still in progress")]
+    IdempotencyKeyInUse,
+
+    #[error("Idempotency key was already used for a different request")]
+    IdempotencyKeyMismatch,
+
     #[error(transparent)]
     ModelError(#[from] models::Error),
 
@@ -19,25 +41,173 @@ pub enum Error {
     Unhandled(#[from] anyhow::Error),
 }
 
+impl Error {
+    /// What we're willing to tell the client about this error.
+    pub fn client_status_and_error(&self) -> (StatusCode, ClientError) {
+        match self {
+            Self::MissingAuthHeader | Self::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                ClientError::new(ErrorCode::Unauthorized, "Unauthorized"),
+            ),
+            Self::RateLimited { retry_after } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ClientError::new(ErrorCode::RateLimited, "Too many requests")
+                    .with_details(json!({ "retry_after": retry_after })),
+            ),
+            Self::UnsupportedFormat(format) => (
+                StatusCode::BAD_REQUEST,
+                ClientError::new(ErrorCode::UnsupportedFormat, "Unsupported format")
+                    .with_details(json!({ "format": format })),
+            ),
+            Self::InvalidIdempotencyKey => (
+                StatusCode::BAD_REQUEST,
+                ClientError::new(
+                    ErrorCode::InvalidIdempotencyKey,
+                    "Idempotency-Key must be 1 to 256 visible ASCII characters",
+                ),
+            ),
+            Self::IdempotencyKeyInUse => (
+                StatusCode::CONFLICT,
+                ClientError::new(
+                    ErrorCode::IdempotencyKeyInUse,
+                    "A request with this idempotency key is still in progress",
+                ),
+            ),
+            Self::IdempotencyKeyMismatch => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ClientError::new(
+                    ErrorCode::IdempotencyKeyMismatch,
+                    "Idempotency key was already used for a different request",
+                ),
+            ),
+            Self::ModelError(models::Error::EntityNotFound { table, id }) => (
+                StatusCode::NOT_FOUND,
+                ClientError::new(ErrorCode::NotFound, "Not found")
+                    .with_details(json!({ "table": table, "id": id })),
+            ),
+            Self::ModelError(models::Error::EmptyBatch(table)) => (
+                StatusCode::BAD_REQUEST,
+                ClientError::new(ErrorCode::EmptyBatch, "Empty batch")
+                    .with_details(json!({ "table": table })),
+            ),
+            Self::ModelError(models::Error::Validation(errors)) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ClientError::new(ErrorCode::ValidationFailed, "Validation failed")
+                    .with_details(json!({ "records": errors })),
+            ),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ClientError::new(ErrorCode::InternalError, "Internal server error"),
+            ),
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        match self {
-            Self::MissingAuthHeader | Self::Unauthorized => {
-                tracing::debug!("{self:?}");
-                (StatusCode::UNAUTHORIZED, "Unauthorized").into_response()
-            }
-            Self::ModelError(models::Error::EntityNotFound { .. }) => {
-                tracing::debug!("{self:?}");
-                (StatusCode::NOT_FOUND, "Not found").into_response()
-            }
-            Self::ModelError(models::Error::EmptyBatch(_)) => {
-                tracing::debug!("{self:?}");
-                (StatusCode::BAD_REQUEST, "Empty batch").into_response()
-            }
-            _ => {
-                tracing::error!("{self:?}");
-                (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
-            }
+        let (status, client_error) = self.client_status_and_error();
+
+        if status.is_server_error() {
+            tracing::error!("{self:?}");
+        } else {
+            tracing::debug!("{self:?}");
         }
+
+        let mut response = client_error.into_response_with_status(status);
+
+        if let Self::RateLimited { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+
+        response
     }
 }
+
+/// Machine-readable codes for every error a client can receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    EmptyBatch,
+    IdempotencyKeyInUse,
+    IdempotencyKeyMismatch,
+    InternalError,
+    InvalidIdempotencyKey,
+    MethodNotAllowed,
+    NotFound,
+    PayloadTooLarge,
+    RateLimited,
+    Unauthorized,
+    UnprocessableEntity,
+    UnsupportedFormat,
+    UnsupportedMediaType,
+    ValidationFailed,
+}
+
+impl From<StatusCode> for ErrorCode {
+    fn from(value: StatusCode) -> Self {
+        match value {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => Self::MethodNotAllowed,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::UnsupportedMediaType,
+            StatusCode::UNPROCESSABLE_ENTITY => Self::UnprocessableEntity,
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited,
+            s if s.is_client_error() => Self::BadRequest,
+            _ => Self::InternalError,
+        }
+    }
+}
+
+/// The body of every error response, wrapped in an `error` object.
+///
+/// The `request_id` is filled in by the response mapping middleware, as we
+/// don't have access to the request when the error is converted.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[cfg_attr(test, derive(serde::Deserialize))]
+pub struct ClientError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<Value>,
+    pub request_id: Option<String>,
+}
+
+impl ClientError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+            request_id: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn into_response_with_status(self, status: StatusCode) -> Response {
+        let mut response = (
+            status,
+            Json(ErrorEnvelope {
+                error: self.clone(),
+            }),
+        )
+            .into_response();
+        // so the response mapper can find and amend this
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// What error responses actually look like on the wire.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorEnvelope {
+    pub error: ClientError,
+}
@@ -1,12 +1,14 @@
 mod cli;
+mod client;
 mod config;
 mod error;
+mod metrics;
 mod middleware;
 mod models;
 mod routes;
 mod server;
+mod telemetry;
 
-pub use config::config;
 pub use error::{Error, Result};
 
 #[tokio::main]
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use prometheus::{
+    histogram_opts, opts, Encoder, GaugeVec, Histogram, HistogramTimer, HistogramVec,
+    IntCounterVec, IntGauge, IntGaugeVec, Registry, TextEncoder,
+};
+
+use crate::models::{BenchmarkBmc, ModelManager};
+
+/// Everything we expose to Prometheus.
+///
+/// Each instance has its own registry, so tests don't see each other's numbers.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    summary_generation: Histogram,
+    pool_connections: IntGaugeVec,
+    pool_max_connections: IntGauge,
+    benchmarks: IntGaugeVec,
+    last_submission: GaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new().expect("metrics are statically defined")
+    }
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("aoc".into()), None)?;
+
+        let requests = IntCounterVec::new(
+            opts!("http_requests_total", "HTTP requests handled."),
+            &["method", "route", "status"],
+        )?;
+
+        let request_duration = HistogramVec::new(
+            histogram_opts!(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests."
+            ),
+            &["method", "route", "status"],
+        )?;
+
+        // a year's worth of benchmarks can take a while
+        let summary_generation = Histogram::with_opts(histogram_opts!(
+            "summary_generation_duration_seconds",
+            "Time taken to generate and store the summaries for a year.",
+            vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+        ))?;
+
+        let pool_connections = IntGaugeVec::new(
+            opts!("db_pool_connections", "Database connections by state."),
+            &["state"],
+        )?;
+
+        let pool_max_connections = IntGauge::with_opts(opts!(
+            "db_pool_max_connections",
+            "The most database connections the pool will open."
+        ))?;
+
+        let benchmarks = IntGaugeVec::new(
+            opts!("benchmarks", "Stored benchmarks per year."),
+            &["year"],
+        )?;
+
+        let last_submission = GaugeVec::new(
+            opts!(
+                "last_submission_timestamp_seconds",
+                "When benchmarks were last written for a year, as a unix timestamp."
+            ),
+            &["year"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(summary_generation.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_max_connections.clone()))?;
+        registry.register(Box::new(benchmarks.clone()))?;
+        registry.register(Box::new(last_submission.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            summary_generation,
+            pool_connections,
+            pool_max_connections,
+            benchmarks,
+            last_submission,
+        })
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Records the time until the returned timer is dropped.
+    pub fn time_summary_generation(&self) -> HistogramTimer {
+        self.summary_generation.start_timer()
+    }
+
+    /// Refresh the values that are read rather than counted, and encode
+    /// everything in the text exposition format.
+    pub async fn render(&self, mm: &ModelManager) -> crate::Result<String> {
+        let pool = mm.pool_stats();
+        self.pool_connections
+            .with_label_values(&["idle"])
+            .set(pool.idle as i64);
+        self.pool_connections
+            .with_label_values(&["in_use"])
+            .set(pool.size as i64 - pool.idle as i64);
+        self.pool_max_connections.set(pool.max as i64);
+
+        // years can disappear when benchmarks are removed
+        self.benchmarks.reset();
+        self.last_submission.reset();
+
+        for stats in BenchmarkBmc::stats(mm).await? {
+            let year = stats.year.to_string();
+
+            self.benchmarks.with_label_values(&[&year]).set(stats.count);
+
+            if let Some(at) = stats.last_submitted {
+                self.last_submission
+                    .with_label_values(&[&year])
+                    .set(at.unix_timestamp() as f64);
+            }
+        }
+
+        let mut out = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .map_err(anyhow::Error::from)?;
+
+        Ok(String::from_utf8(out).map_err(anyhow::Error::from)?)
+    }
+}
@@ -1,26 +1,38 @@
-use axum::{body::Body, http::Request, middleware::Next, response::Response};
+use std::sync::Arc;
+
+use axum::{body::Body, extract::State, http::Request, middleware::Next, response::Response};
 use axum_extra::{
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
 
-use crate::{config, Error, Result};
+use super::request_route;
+use crate::{config::Config, models::AuditContext, Error, Result};
+
+/// There is currently only one token, so it's the only identity we have.
+const API_TOKEN_ACTOR: &str = "api-token";
 
 pub async fn mw_require_auth(
+    State(config): State<Arc<Config>>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    req: Request<Body>,
+    mut req: Request<Body>,
     next: Next,
 ) -> Result<Response> {
     tracing::debug!("authenticating");
     if let Some(bearer) = bearer {
         let token = bearer.token();
 
-        if !config().secret.validate(token) {
+        if !config.secret.validate(token) {
             return Err(Error::Unauthorized);
         }
     } else {
         return Err(Error::MissingAuthHeader);
     }
 
+    let route = request_route(&req);
+
+    req.extensions_mut()
+        .insert(AuditContext::new(API_TOKEN_ACTOR, route));
+
     Ok(next.run(req).await)
 }
@@ -0,0 +1,374 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{FromRequest, State},
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use super::request_route;
+use crate::{
+    config::Config,
+    models::{Claim, IdempotencyBmc, IdempotencyKey, ModelManager, StoredResponse},
+    Error, Result,
+};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY_LEN: usize = 256;
+
+/// Replays the stored response for requests that repeat an `Idempotency-Key`
+/// within the configured window, instead of handling them again.
+///
+/// Only successful responses are stored. Anything else releases the key, as
+/// the request can safely be retried, and so does the request being dropped
+/// before it finishes.
+pub async fn mw_idempotency(
+    State(mm): State<ModelManager>,
+    State(config): State<Arc<Config>>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response> {
+    let window = config.idempotency.window;
+    let lease = config.idempotency.lease;
+
+    let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) if window > 0 => parse_key(key)?,
+        _ => return Ok(next.run(req).await),
+    };
+
+    let route = request_route(&req);
+
+    // we need the whole body to tell if a key is being reused for a different
+    // request, and going through the extractor respects the body limit
+    let (parts, body) = req.into_parts();
+    let body = match Bytes::from_request(Request::from_parts(parts.clone(), body), &()).await {
+        Ok(body) => body,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(parts.uri.to_string());
+    hasher.update(b"\n");
+    hasher.update(&body);
+
+    let key = IdempotencyKey {
+        key,
+        route,
+        request_hash: format!("{:x}", hasher.finalize()),
+    };
+
+    match IdempotencyBmc::claim(
+        &mm,
+        &key,
+        Duration::from_secs(window),
+        Duration::from_secs(lease),
+    )
+    .await?
+    {
+        Claim::Acquired => {}
+        Claim::InProgress => return Err(Error::IdempotencyKeyInUse),
+        Claim::Mismatch => return Err(Error::IdempotencyKeyMismatch),
+        Claim::Completed(stored) => return Ok(replay(stored)),
+    }
+
+    let held = HeldKey::new(mm.clone(), key);
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    if !response.status().is_success() {
+        IdempotencyBmc::release(&mm, &held.disarm()).await?;
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(anyhow::Error::from)?;
+    let key = held.disarm();
+
+    let stored = StoredResponse {
+        status: parts.status.as_u16() as i16,
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from),
+        body: body.to_vec(),
+    };
+
+    // the request has already been handled at this point, so the client should
+    // get the response regardless
+    if let Err(e) = IdempotencyBmc::complete(&mm, &key, &stored).await {
+        tracing::error!("could not store response for idempotency key: {e:?}");
+        if let Err(e) = IdempotencyBmc::release(&mm, &key).await {
+            tracing::error!("could not release idempotency key: {e:?}");
+        }
+    }
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// An acquired key, which is released if the request is dropped while holding
+/// it, e.g. when the client disconnects or shutdown stops waiting for it.
+struct HeldKey {
+    mm: ModelManager,
+    key: Option<IdempotencyKey>,
+}
+
+impl HeldKey {
+    fn new(mm: ModelManager, key: IdempotencyKey) -> Self {
+        Self { mm, key: Some(key) }
+    }
+
+    /// Take back the key, now that the request will complete or release it.
+    fn disarm(mut self) -> IdempotencyKey {
+        self.key.take().expect("only disarmed once")
+    }
+}
+
+impl Drop for HeldKey {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+
+        // if the runtime is already gone the lease will expire instead
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let mm = self.mm.clone();
+        runtime.spawn(async move {
+            if let Err(e) = IdempotencyBmc::release(&mm, &key).await {
+                tracing::error!("could not release abandoned idempotency key: {e:?}");
+            }
+        });
+    }
+}
+
+fn parse_key(value: &HeaderValue) -> Result<String> {
+    let key = value.to_str().map_err(|_| Error::InvalidIdempotencyKey)?;
+
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(Error::InvalidIdempotencyKey);
+    }
+
+    Ok(key.to_string())
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let status = u16::try_from(stored.status)
+        .ok()
+        .and_then(|s| StatusCode::from_u16(s).ok())
+        .unwrap_or(StatusCode::OK);
+
+    let mut response = (status, stored.body).into_response();
+    let headers = response.headers_mut();
+
+    match stored
+        .content_type
+        .and_then(|c| HeaderValue::from_str(&c).ok())
+    {
+        Some(content_type) => headers.insert(header::CONTENT_TYPE, content_type),
+        None => headers.remove(header::CONTENT_TYPE),
+    };
+    headers.insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{extract::State, http::StatusCode, middleware, routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::server::AppState;
+
+    // fails or never finishes the first request it sees, then counts the rest
+    async fn handler(State(count): State<Arc<AtomicUsize>>, Json(body): Json<Value>) -> Response {
+        let n = count.fetch_add(1, Ordering::SeqCst);
+
+        if body["fail"] == json!(true) && n == 0 {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+
+        if body["hang"] == json!(true) && n == 0 {
+            std::future::pending::<()>().await;
+        }
+
+        Json(json!({ "count": n })).into_response()
+    }
+
+    fn app(config: Config, mm: ModelManager) -> (Router, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let app = Router::new()
+            .route("/", post(handler))
+            .with_state(count.clone())
+            .layer(middleware::from_fn_with_state(
+                AppState::new(config, mm),
+                mw_idempotency,
+            ));
+
+        (app, count)
+    }
+
+    fn request(key: Option<&str>, body: Value) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header(header::CONTENT_TYPE, "application/json");
+
+        if let Some(key) = key {
+            builder = builder.header(IDEMPOTENCY_KEY_HEADER, key);
+        }
+
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    async fn body(response: Response) -> Value {
+        let raw = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&raw).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn replays_stored_response(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let (app, count) = app(Config::test(), ModelManager::from(pool));
+
+        let first = app
+            .clone()
+            .oneshot(request(Some("key-1"), json!({})))
+            .await?;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(first.headers().get(REPLAYED_HEADER).is_none());
+        assert_eq!(body(first).await, json!({ "count": 0 }));
+
+        let second = app
+            .clone()
+            .oneshot(request(Some("key-1"), json!({})))
+            .await?;
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.headers()[REPLAYED_HEADER], "true");
+        assert_eq!(second.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body(second).await, json!({ "count": 0 }));
+
+        // a different key is handled normally
+        let third = app.oneshot(request(Some("key-2"), json!({}))).await?;
+        assert_eq!(body(third).await, json!({ "count": 1 }));
+
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn ignores_requests_without_key(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let (app, count) = app(Config::test(), ModelManager::from(pool));
+
+        app.clone().oneshot(request(None, json!({}))).await?;
+        app.oneshot(request(None, json!({}))).await?;
+
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn disabled_by_zero_window(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mut config = Config::test();
+        config.idempotency.window = 0;
+        let (app, count) = app(config, ModelManager::from(pool));
+
+        app.clone()
+            .oneshot(request(Some("key-1"), json!({})))
+            .await?;
+        let second = app.oneshot(request(Some("key-1"), json!({}))).await?;
+
+        assert!(second.headers().get(REPLAYED_HEADER).is_none());
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn rejects_reuse_for_different_request(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let (app, _) = app(Config::test(), ModelManager::from(pool));
+
+        app.clone()
+            .oneshot(request(Some("key-1"), json!({ "a": 1 })))
+            .await?;
+        let response = app
+            .oneshot(request(Some("key-1"), json!({ "a": 2 })))
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn retries_failed_requests(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let (app, count) = app(Config::test(), ModelManager::from(pool));
+
+        let first = app
+            .clone()
+            .oneshot(request(Some("key-1"), json!({ "fail": true })))
+            .await?;
+        assert_eq!(first.status(), StatusCode::BAD_REQUEST);
+
+        let second = app
+            .oneshot(request(Some("key-1"), json!({ "fail": true })))
+            .await?;
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(second.headers().get(REPLAYED_HEADER).is_none());
+
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn releases_abandoned_requests(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let (app, count) = app(Config::test(), ModelManager::from(pool));
+
+        // as if the client went away partway through
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(100),
+            app.clone()
+                .oneshot(request(Some("key-1"), json!({ "hang": true }))),
+        )
+        .await;
+        assert!(abandoned.is_err());
+
+        // the key is released in the background
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let retry = app
+            .oneshot(request(Some("key-1"), json!({ "hang": true })))
+            .await?;
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert!(retry.headers().get(REPLAYED_HEADER).is_none());
+
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_validation() {
+        assert!(parse_key(&HeaderValue::from_static("abc-123")).is_ok());
+        assert!(parse_key(&HeaderValue::from_static("")).is_err());
+        assert!(parse_key(&HeaderValue::from_static("has space")).is_err());
+        assert!(parse_key(&HeaderValue::from_str(&"a".repeat(257)).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use std::time::Instant;
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::metrics::Metrics;
+
+/// Counts and times every request by its route and response status.
+///
+/// This must be added with `route_layer`, as the matched route isn't known
+/// before then, and labelling by the raw path would be unbounded.
+pub async fn mw_track_metrics(
+    State(metrics): State<Metrics>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".into());
+    let method = req.method().clone();
+    let started = Instant::now();
+
+    let response = next.run(req).await;
+
+    metrics.observe_request(
+        method.as_str(),
+        &route,
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+
+    response
+}
@@ -1,3 +1,27 @@
 mod auth;
+mod idempotency;
+mod metrics;
+mod rate_limit;
+mod request_id;
+mod response;
 
 pub use auth::mw_require_auth;
+pub use idempotency::mw_idempotency;
+pub use metrics::mw_track_metrics;
+pub use rate_limit::{mw_rate_limit, RateLimiter};
+pub use request_id::{mw_request_id, REQUEST_ID_HEADER};
+pub use response::mw_response_map;
+
+use axum::{body::Body, extract::OriginalUri, http::Request};
+
+/// The method and path of a request, as the client sent it.
+fn request_route(req: &Request<Body>) -> String {
+    // nested routers strip the prefix from the uri, so we want the original
+    let path = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|u| u.path().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+
+    format!("{} {}", req.method(), path)
+}
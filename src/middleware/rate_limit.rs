@@ -0,0 +1,282 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+
+use crate::{config::RateLimit, Error, Result};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// past this many tracked clients we start evicting the ones that are back to a
+// full bucket, as they would be indistinguishable from a new client anyway
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ClientKey {
+    Ip(IpAddr),
+    // we don't want to hold on to the raw token, even in memory
+    Token(u64),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket rate limiter keyed by client IP and bearer token.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    trusted_proxies: Arc<[IpAddr]>,
+    buckets: Arc<Mutex<HashMap<ClientKey, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, trusted_proxies: &[IpAddr]) -> Self {
+        Self {
+            limit,
+            trusted_proxies: trusted_proxies.into(),
+            buckets: Arc::default(),
+        }
+    }
+
+    /// The address to limit a request by. `peer` is `None` for connections
+    /// over a Unix socket, which only a local proxy should be using.
+    fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let trusted = |ip: &IpAddr| self.trusted_proxies.contains(ip);
+
+        if peer.is_some_and(|ip| !trusted(&ip)) {
+            return peer;
+        }
+
+        let forwarded: Vec<_> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().parse::<IpAddr>())
+            .collect();
+
+        // each proxy appends the address it saw, so the first one from the
+        // right that isn't ours is the client. Anything to the left of that was
+        // supplied by the client and can't be trusted.
+        for ip in forwarded.into_iter().rev() {
+            match ip {
+                Ok(ip) if trusted(&ip) => continue,
+                Ok(ip) => return Some(ip),
+                Err(_) => break,
+            }
+        }
+
+        peer
+    }
+
+    pub fn check_ip(&self, ip: IpAddr) -> Result<()> {
+        self.check(ClientKey::Ip(ip), self.limit.per_ip, Instant::now())
+    }
+
+    pub fn check_token(&self, token: &str) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        token.hash(&mut hasher);
+
+        self.check(
+            ClientKey::Token(hasher.finish()),
+            self.limit.per_token,
+            Instant::now(),
+        )
+    }
+
+    fn check(&self, key: ClientKey, capacity: u32, now: Instant) -> Result<()> {
+        if capacity == 0 || self.limit.period == 0 {
+            return Ok(());
+        }
+
+        let capacity = capacity as f64;
+        let rate = capacity / self.limit.period as f64;
+
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, b| {
+                b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+
+        Err(Error::RateLimited {
+            // round up so clients that respect the header don't hit us early
+            retry_after: wait.as_secs() + u64::from(wait.subsec_nanos() > 0),
+        })
+    }
+}
+
+pub async fn mw_rate_limit(
+    State(limiter): State<RateLimiter>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response> {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+
+    if let Some(ip) = limiter.client_ip(peer, req.headers()) {
+        limiter.check_ip(ip)?;
+    }
+
+    if let Some(bearer) = bearer {
+        limiter.check_token(bearer.token())?;
+    }
+
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::{
+        extract::connect_info::MockConnectInfo,
+        http::{header, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn limiter(per_ip: u32, per_token: u32, period: u64) -> RateLimiter {
+        RateLimiter::new(
+            RateLimit {
+                per_ip,
+                per_token,
+                period,
+            },
+            &[],
+        )
+    }
+
+    #[test]
+    fn limits_and_refills() {
+        let limiter = limiter(2, 0, 10);
+        let key = ClientKey::Ip(Ipv4Addr::LOCALHOST.into());
+        let start = Instant::now();
+
+        assert!(limiter.check(key, 2, start).is_ok());
+        assert!(limiter.check(key, 2, start).is_ok());
+
+        let res = limiter.check(key, 2, start);
+        assert!(matches!(res, Err(Error::RateLimited { retry_after: 5 })));
+
+        // other clients are unaffected
+        let other = ClientKey::Ip(Ipv4Addr::new(10, 0, 0, 1).into());
+        assert!(limiter.check(other, 2, start).is_ok());
+
+        // we refill at 2 per 10 seconds
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.check(key, 2, later).is_ok());
+        assert!(limiter.check(key, 2, later).is_err());
+    }
+
+    #[test]
+    fn zero_disables() {
+        let limiter = limiter(0, 0, 10);
+
+        for _ in 0..100 {
+            assert!(limiter.check_ip(Ipv4Addr::LOCALHOST.into()).is_ok());
+            assert!(limiter.check_token("sandcastle").is_ok());
+        }
+    }
+
+    #[test]
+    fn trusts_forwarded_for_from_proxies() {
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let client = IpAddr::from([203, 0, 113, 7]);
+        let limiter = RateLimiter::new(limiter(1, 0, 10).limit, &[proxy]);
+
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(X_FORWARDED_FOR, value.parse().unwrap());
+            headers
+        };
+
+        // the client can put anything it likes at the start
+        let forwarded = headers("192.0.2.1, 203.0.113.7, 10.0.0.1");
+        assert_eq!(limiter.client_ip(Some(proxy), &forwarded), Some(client));
+        assert_eq!(limiter.client_ip(None, &forwarded), Some(client));
+
+        // but only proxies get to say who the client is
+        assert_eq!(
+            limiter.client_ip(Some(client), &headers("192.0.2.1")),
+            Some(client)
+        );
+
+        assert_eq!(
+            limiter.client_ip(Some(proxy), &headers("junk")),
+            Some(proxy)
+        );
+        assert_eq!(
+            limiter.client_ip(Some(proxy), &HeaderMap::new()),
+            Some(proxy)
+        );
+        assert_eq!(limiter.client_ip(None, &HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn responds_with_retry_after() -> anyhow::Result<()> {
+        let app = Router::new()
+            .route("/", get(|| async { "hello" }))
+            .layer(middleware::from_fn_with_state(
+                limiter(10, 1, 60),
+                mw_rate_limit,
+            ))
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+
+        let req = || {
+            Request::builder()
+                .uri("/")
+                .header(header::AUTHORIZATION, "Bearer sandcastle")
+                .body(Body::empty())
+        };
+
+        let response = app.clone().oneshot(req()?).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.oneshot(req()?).await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+
+        Ok(())
+    }
+}
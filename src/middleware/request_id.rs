@@ -0,0 +1,106 @@
+use axum::{
+    body::Body,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// generous enough for any tracing or proxy id format we're likely to see
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Makes sure every request has an `X-Request-Id`, keeping the one the client
+/// or a proxy sent if it looks sane, and echoes it in the response.
+///
+/// This must wrap the trace layer and [super::mw_response_map], which pick the
+/// id up from the request headers.
+pub async fn mw_request_id(mut req: Request<Body>, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|v| is_valid(v))
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&Uuid::new_v4().to_string()).expect("uuids are valid headers")
+        });
+
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+
+    response
+}
+
+// this ends up in every log line, so we don't want anything that could be
+// mistaken for something else
+fn is_valid(value: &HeaderValue) -> bool {
+    let value = value.as_bytes();
+
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value.iter().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn app() -> Router {
+        // echo back what the handler saw, to check it matches the response
+        Router::new()
+            .route(
+                "/",
+                get(|req: Request<Body>| async move {
+                    req.headers()[REQUEST_ID_HEADER]
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                }),
+            )
+            .layer(middleware::from_fn(mw_request_id))
+    }
+
+    async fn request_id(header: Option<&str>) -> anyhow::Result<(String, String)> {
+        let mut req = Request::builder().uri("/");
+        if let Some(header) = header {
+            req = req.header(REQUEST_ID_HEADER, header);
+        }
+
+        let response = app().oneshot(req.body(Body::empty())?).await?;
+        let echoed = response.headers()[REQUEST_ID_HEADER].to_str()?.to_string();
+        let seen = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+
+        Ok((String::from_utf8(seen.to_vec())?, echoed))
+    }
+
+    #[tokio::test]
+    async fn keeps_valid_ids() -> anyhow::Result<()> {
+        let (seen, echoed) = request_id(Some("abc-123")).await?;
+
+        assert_eq!(seen, "abc-123");
+        assert_eq!(echoed, "abc-123");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generates_missing_or_invalid_ids() -> anyhow::Result<()> {
+        let long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+
+        for header in [None, Some(""), Some("has spaces"), Some(long.as_str())] {
+            let (seen, echoed) = request_id(header).await?;
+
+            assert_eq!(seen, echoed);
+            assert!(Uuid::parse_str(&seen).is_ok(), "{header:?} became {seen}");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use super::REQUEST_ID_HEADER;
+use crate::error::{ClientError, ErrorCode, ErrorEnvelope};
+
+// extractor rejections are short messages, anything larger than this is not
+// something we want to echo back
+const MAX_REJECTION_SIZE: usize = 4096;
+
+/// Ensures every error response is a JSON envelope containing a request id.
+///
+/// This must be wrapped by [super::mw_request_id], which provides the id.
+///
+/// Our own errors arrive with a [ClientError] attached, but rejections from
+/// extractors (malformed json, oversized bodies, etc.) are plain text, so we
+/// wrap those as well. Handlers that already answer in JSON (like the health
+/// checks) are left alone.
+pub async fn mw_response_map(req: Request<Body>, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
+    let response = next.run(req).await;
+    let status = response.status();
+
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    let mut client_error = match parts.extensions.remove::<ClientError>() {
+        Some(e) => e,
+        None if is_json(&parts.headers) => return Response::from_parts(parts, body),
+        None => {
+            let raw = to_bytes(body, MAX_REJECTION_SIZE).await.unwrap_or_default();
+            let message = if raw.is_empty() {
+                status.canonical_reason().unwrap_or_default().to_string()
+            } else {
+                String::from_utf8_lossy(&raw).into_owned()
+            };
+
+            ClientError::new(ErrorCode::from(status), message)
+        }
+    };
+
+    client_error.request_id = request_id;
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+
+    let body = serde_json::to_string(&ErrorEnvelope {
+        error: client_error,
+    })
+    .unwrap_or_default();
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::StatusCode,
+        middleware,
+        routing::{get, post},
+        Json, Router,
+    };
+    use serde::Deserialize;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use crate::Error;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Envelope {
+        error: ClientError,
+    }
+
+    fn app() -> Router {
+        Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route(
+                "/err",
+                get(|| async { Err::<(), _>(Error::RateLimited { retry_after: 3 }) }),
+            )
+            .route(
+                "/json",
+                post(|Json(v): Json<i32>| async move { v.to_string() }),
+            )
+            .route(
+                "/unavailable",
+                get(|| async {
+                    (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        Json(json!({"status": "down"})),
+                    )
+                }),
+            )
+            .layer(middleware::from_fn(mw_response_map))
+            .layer(middleware::from_fn(crate::middleware::mw_request_id))
+    }
+
+    async fn envelope(response: Response) -> anyhow::Result<ClientError> {
+        let raw = to_bytes(response.into_body(), usize::MAX).await?;
+        Ok(serde_json::from_slice::<Envelope>(&raw)?.error)
+    }
+
+    #[tokio::test]
+    async fn leaves_success_alone() -> anyhow::Result<()> {
+        let response = app()
+            .oneshot(Request::builder().uri("/ok").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let raw = to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&raw[..], b"ok");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adds_request_id_to_errors() -> anyhow::Result<()> {
+        let response = app()
+            .oneshot(
+                Request::builder()
+                    .uri("/err")
+                    .header(REQUEST_ID_HEADER, "abc-123")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+
+        let error = envelope(response).await?;
+        assert_eq!(error.code, ErrorCode::RateLimited);
+        assert_eq!(error.details, Some(json!({"retry_after": 3})));
+        assert_eq!(error.request_id.as_deref(), Some("abc-123"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wraps_rejections() -> anyhow::Result<()> {
+        let response = app()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/json")
+                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from("not json"))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            mime::APPLICATION_JSON.as_ref()
+        );
+
+        let error = envelope(response).await?;
+        assert_eq!(error.code, ErrorCode::BadRequest);
+        assert!(error.request_id.is_some());
+
+        // and routing failures
+        let response = app()
+            .oneshot(Request::builder().uri("/nope").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(envelope(response).await?.code, ErrorCode::NotFound);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_json_errors_alone() -> anyhow::Result<()> {
+        let response = app()
+            .oneshot(Request::builder().uri("/unavailable").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let raw = to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&raw)?,
+            json!({"status": "down"})
+        );
+
+        Ok(())
+    }
+}
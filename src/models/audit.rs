@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+
+use sea_query::{Cond, Expr, Iden, Order, PostgresQueryBuilder, Query};
+use sea_query_binder::SqlxBinder;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{FromRow, PgConnection};
+use strum::{EnumIter, IntoEnumIterator};
+use time::OffsetDateTime;
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+
+use super::{base::DbBmc, outcome::BatchOutcome, ModelManager, Result};
+
+const DEFAULT_LIMIT: u64 = 100;
+const MAX_LIMIT: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize, ToSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct AuditEntry {
+    pub id: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    pub actor: String,
+    pub route: String,
+    pub action: String,
+    pub entity: String,
+    pub year: Option<i32>,
+    pub participant: Option<String>,
+    pub keys: Value,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+}
+
+/// Who is performing a modification, and through what.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditContext {
+    pub actor: String,
+    pub route: String,
+}
+
+impl AuditContext {
+    pub fn new(actor: impl Into<String>, route: impl Into<String>) -> Self {
+        Self {
+            actor: actor.into(),
+            route: route.into(),
+        }
+    }
+}
+
+/// Something that can be recorded in the audit log.
+///
+/// The keys are expected to uniquely identify the record within its table, and
+/// are what we use to pair up the stored and submitted versions of a record.
+pub trait Auditable: Serialize {
+    fn audit_keys(&self) -> Value;
+
+    fn audit_year(&self) -> i32;
+
+    fn audit_participant(&self) -> &str;
+}
+
+impl<T: Auditable> Auditable for &T {
+    fn audit_keys(&self) -> Value {
+        (*self).audit_keys()
+    }
+
+    fn audit_year(&self) -> i32 {
+        (*self).audit_year()
+    }
+
+    fn audit_participant(&self) -> &str {
+        (*self).audit_participant()
+    }
+}
+
+// this sucks, but we have to wait for a newer version of sea-query to allow
+// more control over the struct proc macro
+#[derive(Debug, Clone, Copy, Iden, EnumIter)]
+pub enum AuditIden {
+    #[iden = "audit_log"]
+    Table,
+    Id,
+    CreatedAt,
+    Actor,
+    Route,
+    Action,
+    Entity,
+    Year,
+    Participant,
+    Keys,
+    Before,
+    After,
+}
+
+pub struct AuditBmc;
+
+impl DbBmc for AuditBmc {
+    const TABLE: &'static str = "audit_log";
+    type Iden = AuditIden;
+
+    fn table_iden() -> Self::Iden {
+        Self::Iden::Table
+    }
+}
+
+impl AuditBmc {
+    /// Record the result of an upsert of `after` into the table for `MC`, where
+    /// `before` contains whatever was stored for those same keys prior to the
+    /// upsert.
+    ///
+    /// Updates only record the fields that were changed.
+    #[instrument(name = "AuditBmc::record_upserts", skip_all, fields(table = MC::TABLE))]
+    pub(super) async fn record_upserts<MC, B, A>(
+        conn: &mut PgConnection,
+        ctx: &AuditContext,
+        before: &[B],
+        after: &[A],
+    ) -> Result<()>
+    where
+        MC: DbBmc,
+        B: Auditable,
+        A: Auditable,
+    {
+        if after.is_empty() {
+            return Ok(());
+        }
+
+        // Value is not hashable, but its string form is good enough here
+        let before: HashMap<String, &B> = before
+            .iter()
+            .map(|b| (b.audit_keys().to_string(), b))
+            .collect();
+
+        let mut query = Query::insert();
+
+        query.into_table(AuditIden::Table).columns([
+            AuditIden::Actor,
+            AuditIden::Route,
+            AuditIden::Action,
+            AuditIden::Entity,
+            AuditIden::Year,
+            AuditIden::Participant,
+            AuditIden::Keys,
+            AuditIden::Before,
+            AuditIden::After,
+        ]);
+
+        for record in after {
+            let keys = record.audit_keys();
+            let new = serde_json::to_value(record).map_err(anyhow::Error::from)?;
+
+            let (action, old, new) = match before.get(&keys.to_string()) {
+                Some(existing) => {
+                    let old = serde_json::to_value(existing).map_err(anyhow::Error::from)?;
+                    let (old, new) = diff(&old, &new);
+                    (AuditAction::Update, Some(old), new)
+                }
+                None => (AuditAction::Create, None, new),
+            };
+
+            query.values_panic([
+                ctx.actor.clone().into(),
+                ctx.route.clone().into(),
+                action.as_ref().into(),
+                MC::TABLE.into(),
+                record.audit_year().into(),
+                record.audit_participant().into(),
+                keys.into(),
+                old.into(),
+                Some(new).into(),
+            ]);
+        }
+
+        let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values).execute(conn).await?;
+
+        Ok(())
+    }
+
+    /// Record the records from `data` that were actually written according to
+    /// the `outcomes` of a batch upsert.
+    #[instrument(name = "AuditBmc::record_outcomes", skip_all, fields(table = MC::TABLE))]
+    pub(super) async fn record_outcomes<MC, A, T>(
+        conn: &mut PgConnection,
+        ctx: &AuditContext,
+        data: &[A],
+        outcomes: &[BatchOutcome<T>],
+    ) -> Result<()>
+    where
+        MC: DbBmc,
+        A: Auditable,
+        T: Auditable,
+    {
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+
+        for outcome in outcomes.iter().filter(|o| o.is_write()) {
+            after.push(&data[outcome.index]);
+            before.extend(outcome.previous.as_ref());
+        }
+
+        Self::record_upserts::<MC, _, _>(conn, ctx, &before, &after).await
+    }
+
+    /// Unlike the other models, this is ordered newest first and limited.
+    #[instrument(name = "AuditBmc::list", skip(mm))]
+    pub async fn list(mm: &ModelManager, filter: AuditFilter) -> Result<Vec<AuditEntry>> {
+        let db = mm.db();
+
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+        let (sql, values) = Query::select()
+            .columns(AuditIden::iter().filter(|c| !matches!(c, AuditIden::Table)))
+            .from(AuditIden::Table)
+            .cond_where(Cond::from(filter))
+            .order_by(AuditIden::CreatedAt, Order::Desc)
+            .order_by(AuditIden::Id, Order::Desc)
+            .limit(limit)
+            .build_sqlx(PostgresQueryBuilder);
+
+        let entities = sqlx::query_as_with(&sql, values).fetch_all(db).await?;
+
+        Ok(entities)
+    }
+}
+
+/// Reduce two json objects to only the fields of `new` that differ.
+fn diff(old: &Value, new: &Value) -> (Value, Value) {
+    let mut old_out = Map::new();
+    let mut new_out = Map::new();
+
+    if let Value::Object(new) = new {
+        for (k, v) in new {
+            let prev = old.get(k).unwrap_or(&Value::Null);
+            if prev != v {
+                old_out.insert(k.clone(), prev.clone());
+                new_out.insert(k.clone(), v.clone());
+            }
+        }
+    }
+
+    (Value::Object(old_out), Value::Object(new_out))
+}
+
+#[derive(Debug, Default, Clone, Deserialize, IntoParams)]
+#[cfg_attr(test, derive(Serialize))]
+#[into_params(parameter_in = Query)]
+pub struct AuditFilter {
+    pub participant: Option<String>,
+    pub year: Option<i32>,
+    pub entity: Option<String>,
+    pub actor: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub since: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub until: Option<OffsetDateTime>,
+    pub limit: Option<u64>,
+}
+
+impl From<AuditFilter> for Cond {
+    fn from(value: AuditFilter) -> Self {
+        let mut cond = Cond::all();
+
+        if let Some(participant) = value.participant {
+            cond = cond.add(Expr::col(AuditIden::Participant).eq(participant));
+        }
+
+        if let Some(year) = value.year {
+            cond = cond.add(Expr::col(AuditIden::Year).eq(year));
+        }
+
+        if let Some(entity) = value.entity {
+            cond = cond.add(Expr::col(AuditIden::Entity).eq(entity));
+        }
+
+        if let Some(actor) = value.actor {
+            cond = cond.add(Expr::col(AuditIden::Actor).eq(actor));
+        }
+
+        if let Some(since) = value.since {
+            cond = cond.add(Expr::col(AuditIden::CreatedAt).gte(since));
+        }
+
+        if let Some(until) = value.until {
+            cond = cond.add(Expr::col(AuditIden::CreatedAt).lt(until));
+        }
+
+        cond
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::models::{base::UpsertBmc, Participant, ParticipantBmc};
+
+    use super::*;
+
+    #[test]
+    fn diff_only_includes_changes() {
+        let (old, new) = diff(
+            &json!({"id": 1, "year": 2023, "language": "ruby", "mean": 1.0}),
+            &json!({"year": 2023, "language": "rust", "mean": 1.0}),
+        );
+
+        assert_eq!(old, json!({"language": "ruby"}));
+        assert_eq!(new, json!({"language": "rust"}));
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/participants.sql"))]
+    async fn test_record_upserts_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let ctx = AuditContext::new("tester", "POST /api/v1/participants");
+
+        let data = vec![
+            Participant {
+                year: 2023,
+                name: "foo".into(),
+                language: "ruby".into(),
+                repo: "https://foobar/foo3".into(),
+            },
+            Participant {
+                year: 2023,
+                name: "baz".into(),
+                language: "go".into(),
+                repo: "https://foobar/baz".into(),
+            },
+        ];
+
+        let mut conn = mm.db().acquire().await?;
+
+        let before = ParticipantBmc::list_by_keys(&mut conn, &data).await?;
+        assert_eq!(before.len(), 1);
+
+        AuditBmc::record_upserts::<ParticipantBmc, _, _>(&mut conn, &ctx, &before, &data).await?;
+
+        let entries = AuditBmc::list(&mm, AuditFilter::default()).await?;
+        assert_eq!(entries.len(), 2);
+
+        let update = entries.iter().find(|e| e.action == "update").unwrap();
+        assert_eq!(update.actor, "tester");
+        assert_eq!(update.participant.as_deref(), Some("foo"));
+        assert_eq!(update.keys, json!({"year": 2023, "name": "foo"}));
+        assert_eq!(update.before, Some(json!({"repo": "https://foobar/foo2"})));
+        assert_eq!(update.after, Some(json!({"repo": "https://foobar/foo3"})));
+
+        let create = entries.iter().find(|e| e.action == "create").unwrap();
+        assert_eq!(create.before, None);
+
+        let entries = AuditBmc::list(
+            &mm,
+            AuditFilter {
+                participant: Some("baz".into()),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(entries.len(), 1);
+
+        let entries = AuditBmc::list(
+            &mm,
+            AuditFilter {
+                since: Some(OffsetDateTime::now_utc() + time::Duration::hours(1)),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert!(entries.is_empty());
+
+        Ok(())
+    }
+}
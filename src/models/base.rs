@@ -1,9 +1,20 @@
+use std::collections::HashMap;
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use futures::{stream::BoxStream, TryStreamExt};
 use sea_query::{Cond, PostgresQueryBuilder, Query};
 use sea_query_binder::SqlxBinder;
-use sqlx::{postgres::PgRow, FromRow};
+use sqlx::{postgres::PgRow, FromRow, PgConnection};
 use strum::IntoEnumIterator;
+use tracing::{instrument, Instrument};
 
-use super::{ModelManager, Result};
+use super::{
+    audit::{AuditBmc, AuditContext, Auditable},
+    outcome::BatchOutcome,
+    validation::{FieldError, RecordErrors, Validate, ValidationErrors},
+    EntityId, Error, ModelManager, Result,
+};
 
 pub trait DbBmc {
     const TABLE: &'static str;
@@ -14,13 +25,26 @@ pub trait DbBmc {
     fn table_iden() -> Self::Iden;
 }
 
+#[instrument(skip_all, fields(table = MC::TABLE))]
 pub async fn bmc_list<MC, T, F>(mm: &ModelManager, filter: F) -> Result<Vec<T>>
 where
     MC: DbBmc,
     Cond: From<F>,
-    T: for<'r> FromRow<'r, PgRow> + Unpin + Send,
+    T: for<'r> FromRow<'r, PgRow> + Unpin + Send + 'static,
+{
+    bmc_stream::<MC, T, F>(mm, filter).try_collect().await
+}
+
+/// Like [bmc_list], but yields rows as they arrive from the database instead
+/// of holding the whole result in memory.
+pub fn bmc_stream<MC, T, F>(mm: &ModelManager, filter: F) -> BoxStream<'static, Result<T>>
+where
+    MC: DbBmc,
+    Cond: From<F>,
+    T: for<'r> FromRow<'r, PgRow> + Unpin + Send + 'static,
 {
-    let db = mm.db();
+    // the pool is just a handle, cloning it lets the stream outlive the manager
+    let db = mm.db().clone();
 
     let mut query = Query::select();
 
@@ -31,7 +55,195 @@ where
 
     let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
 
-    let entities = sqlx::query_as_with(&sql, values).fetch_all(db).await?;
+    // the query only runs as the stream is polled, which is usually after the
+    // caller's span has closed, so it gets its own for as long as it lives
+    let span = tracing::info_span!("bmc_stream", table = MC::TABLE);
+
+    Box::pin(try_stream! {
+        let mut rows = sqlx::query_as_with::<_, T, _>(&sql, values).fetch(&db);
+
+        while let Some(row) = rows.try_next().instrument(span.clone()).await? {
+            yield row;
+        }
+    })
+}
+
+/// Like [bmc_list], but on a connection that may be part of a transaction.
+pub async fn bmc_list_on<MC, T, F>(conn: &mut PgConnection, filter: F) -> Result<Vec<T>>
+where
+    MC: DbBmc,
+    Cond: From<F>,
+    T: for<'r> FromRow<'r, PgRow> + Unpin + Send + 'static,
+{
+    let (sql, values) = Query::select()
+        .columns(MC::Iden::iter())
+        .from(MC::table_iden())
+        .cond_where(Cond::from(filter))
+        .build_sqlx(PostgresQueryBuilder);
+
+    Ok(sqlx::query_as_with(&sql, values).fetch_all(conn).await?)
+}
+
+/// A model that supports batch upserts that report what happened to each
+/// record.
+#[async_trait]
+pub trait UpsertBmc: DbBmc {
+    /// What is submitted.
+    type Create: Validate + Auditable + Clone + Send + Sync;
+
+    /// What is stored.
+    type Entity: Auditable + Clone + Send;
+
+    /// Find the stored versions of the given records, if any.
+    async fn list_by_keys(
+        conn: &mut PgConnection,
+        data: &[Self::Create],
+    ) -> Result<Vec<Self::Entity>>;
+
+    /// Write the records, which are guaranteed to have unique keys, returning
+    /// the id of each and whether or not it was newly inserted, in order.
+    async fn upsert(
+        conn: &mut PgConnection,
+        data: &[Self::Create],
+    ) -> Result<Vec<(EntityId, bool)>>;
+
+    fn entity_id(stored: &Self::Entity) -> EntityId;
+
+    /// If writing `data` would not change anything about `stored`.
+    fn is_unchanged(data: &Self::Create, stored: &Self::Entity) -> bool;
+}
+
+/// Create or update the given records, skipping the ones that are identical to
+/// what is already stored, and record what was written in the audit log.
+///
+/// Normally every record must be valid for anything to be written. If
+/// `partial` is set, the invalid records are instead rejected individually.
+/// The outcomes are in the same order as the submitted records.
+#[instrument(skip_all, fields(table = MC::TABLE, records = data.len(), partial))]
+pub async fn bmc_upsert<MC>(
+    mm: &ModelManager,
+    ctx: &AuditContext,
+    data: Vec<MC::Create>,
+    partial: bool,
+) -> Result<Vec<BatchOutcome<MC::Entity>>>
+where
+    MC: UpsertBmc,
+{
+    if data.is_empty() {
+        return Err(Error::EmptyBatch(MC::TABLE));
+    }
+
+    let mut outcomes: Vec<Option<BatchOutcome<MC::Entity>>> =
+        (0..data.len()).map(|_| None).collect();
+    let mut invalid = Vec::new();
+    // the upsert cannot touch the same row twice, so only the first occurrence
+    // of a key is considered
+    let mut seen: HashMap<String, usize> = HashMap::default();
+    let mut candidates = Vec::new();
+
+    for (index, record) in data.iter().enumerate() {
+        let errors = record.validate();
+        if !errors.is_empty() {
+            invalid.push(RecordErrors {
+                index,
+                fields: errors.clone(),
+            });
+            outcomes[index] = Some(BatchOutcome::rejected(index, "invalid", errors));
+            continue;
+        }
+
+        let key = record.audit_keys().to_string();
+        if let Some(first) = seen.get(&key) {
+            let errors = vec![FieldError {
+                field: "key",
+                message: format!("duplicates record {first}"),
+            }];
+            invalid.push(RecordErrors {
+                index,
+                fields: errors.clone(),
+            });
+            outcomes[index] = Some(BatchOutcome::rejected(index, "duplicate", errors));
+            continue;
+        }
+
+        seen.insert(key, index);
+        candidates.push(index);
+    }
+
+    if !partial && !invalid.is_empty() {
+        return Err(Error::Validation(ValidationErrors(invalid)));
+    }
+
+    let valid: Vec<_> = candidates.iter().map(|i| data[*i].clone()).collect();
+
+    // either everything, including the audit entries, is written or nothing is
+    let mut tx = mm.db().begin().await?;
+
+    lock_keys::<MC>(&mut tx, &valid).await?;
+
+    let mut existing: HashMap<String, MC::Entity> = MC::list_by_keys(&mut tx, &valid)
+        .await?
+        .into_iter()
+        .map(|e| (e.audit_keys().to_string(), e))
+        .collect();
+
+    let mut to_write = Vec::new();
+    let mut previous = Vec::new();
+
+    for index in candidates {
+        let record = &data[index];
+        match existing.remove(&record.audit_keys().to_string()) {
+            Some(stored) if MC::is_unchanged(record, &stored) => {
+                outcomes[index] = Some(BatchOutcome::unchanged(index, MC::entity_id(&stored)));
+            }
+            stored => {
+                to_write.push(index);
+                previous.push(stored);
+            }
+        }
+    }
+
+    if !to_write.is_empty() {
+        let records: Vec<_> = to_write.iter().map(|i| data[*i].clone()).collect();
+        let written = MC::upsert(&mut tx, &records).await?;
+
+        for ((index, (id, inserted)), stored) in to_write.into_iter().zip(written).zip(previous) {
+            outcomes[index] = Some(BatchOutcome::written(index, id, inserted, stored));
+        }
+    }
+
+    // every index was assigned exactly one outcome above
+    let outcomes: Vec<_> = outcomes.into_iter().flatten().collect();
+
+    AuditBmc::record_outcomes::<MC, _, _>(&mut tx, ctx, &data, &outcomes).await?;
+
+    tx.commit().await?;
+
+    Ok(outcomes)
+}
+
+/// Hold a lock on each of the keys of `data` until the end of the transaction,
+/// so concurrent writers of the same records can't change them between us
+/// reading the previous versions and writing the new ones.
+async fn lock_keys<MC>(conn: &mut PgConnection, data: &[MC::Create]) -> Result<()>
+where
+    MC: UpsertBmc,
+{
+    let keys: Vec<String> = data
+        .iter()
+        .map(|r| format!("{}:{}", MC::TABLE, r.audit_keys()))
+        .collect();
+
+    // always locking in the same order means two batches can't deadlock, and
+    // a hash collision only costs us some concurrency
+    sqlx::query(
+        "SELECT pg_advisory_xact_lock(k) FROM (
+            SELECT DISTINCT hashtextextended(key, 0) AS k FROM unnest($1::text[]) AS key ORDER BY k
+        ) AS keys",
+    )
+    .bind(&keys)
+    .execute(conn)
+    .await?;
 
-    Ok(entities)
+    Ok(())
 }
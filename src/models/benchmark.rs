@@ -1,15 +1,24 @@
+use async_trait::async_trait;
+use futures::stream::BoxStream;
 use sea_query::{Cond, Expr, Iden, PostgresQueryBuilder, Query};
 use sea_query_binder::SqlxBinder;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use serde_json::{json, Value};
+use sqlx::{FromRow, PgConnection};
 use strum::{EnumIter, IntoEnumIterator};
+use time::OffsetDateTime;
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
 
 use super::{
-    base::{bmc_list, DbBmc},
-    Error, ModelManager, Result,
+    audit::{AuditBmc, AuditContext, Auditable},
+    base::{bmc_list, bmc_list_on, bmc_stream, bmc_upsert, DbBmc, UpsertBmc},
+    outcome::BatchOutcome,
+    validation::{valid_years, FieldError, Validate, Validator},
+    EntityId, Error, ModelManager, Result,
 };
 
-#[derive(Debug, Default, Clone, PartialEq, PartialOrd, FromRow, Serialize)]
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd, FromRow, Serialize, ToSchema)]
 #[cfg_attr(test, derive(Deserialize))]
 pub struct Benchmark {
     pub id: i32,
@@ -31,6 +40,14 @@ pub struct Benchmark {
     pub max: f64,
 }
 
+/// How many benchmarks are stored for a year, and when one was last written.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct YearStats {
+    pub year: i32,
+    pub count: i64,
+    pub last_submitted: Option<OffsetDateTime>,
+}
+
 // this sucks, but we have to wait for a newer version of sea-query to allow
 // more control over the struct proc macro
 #[derive(Debug, Clone, Copy, Iden, EnumIter)]
@@ -70,19 +87,128 @@ impl DbBmc for BenchmarkBmc {
 impl BenchmarkBmc {
     /// Create a new Benchmark or update an existing one on a collision.
     #[allow(dead_code)]
-    pub async fn create_or_update(mm: &ModelManager, data: BenchmarkCreate) -> Result<i32> {
-        let ids = Self::batch_create_or_update(mm, [data]).await?;
+    pub async fn create_or_update(
+        mm: &ModelManager,
+        ctx: &AuditContext,
+        data: BenchmarkCreate,
+    ) -> Result<BatchOutcome<Benchmark>> {
+        let mut outcomes = Self::batch_create_or_update(mm, ctx, [data]).await?;
+
+        Ok(outcomes.remove(0))
+    }
 
-        Ok(ids[0])
+    /// Create new Benchmarks or update existing ones on a collision, reporting
+    /// what happened to each.
+    pub async fn batch_create_or_update<Iter>(
+        mm: &ModelManager,
+        ctx: &AuditContext,
+        data: Iter,
+    ) -> Result<Vec<BatchOutcome<Benchmark>>>
+    where
+        Iter: IntoIterator<Item = BenchmarkCreate>,
+    {
+        bmc_upsert::<Self>(mm, ctx, data.into_iter().collect(), false).await
     }
 
-    /// Create new Benchmarks or update an existing ones on a collision.
-    pub async fn batch_create_or_update<Iter>(mm: &ModelManager, data: Iter) -> Result<Vec<i32>>
+    /// Like [Self::batch_create_or_update], but invalid records are rejected
+    /// individually instead of failing the entire batch.
+    pub async fn batch_create_or_update_partial<Iter>(
+        mm: &ModelManager,
+        ctx: &AuditContext,
+        data: Iter,
+    ) -> Result<Vec<BatchOutcome<Benchmark>>>
     where
         Iter: IntoIterator<Item = BenchmarkCreate>,
     {
+        bmc_upsert::<Self>(mm, ctx, data.into_iter().collect(), true).await
+    }
+
+    pub async fn list(mm: &ModelManager, filter: BenchmarkFilter) -> Result<Vec<Benchmark>> {
+        bmc_list::<Self, _, _>(mm, filter).await
+    }
+
+    /// Stream every benchmark matching `filter`, for results too large to list.
+    pub fn stream(
+        mm: &ModelManager,
+        filter: BenchmarkFilter,
+    ) -> BoxStream<'static, Result<Benchmark>> {
+        bmc_stream::<Self, _, _>(mm, filter)
+    }
+
+    /// Totals for every year with benchmarks, ordered by year.
+    ///
+    /// We don't keep timestamps on the benchmarks themselves, so submissions
+    /// come from the audit log, which only has the ones that changed something.
+    #[instrument(name = "BenchmarkBmc::stats", skip_all)]
+    pub async fn stats(mm: &ModelManager) -> Result<Vec<YearStats>> {
         let db = mm.db();
 
+        let stats = sqlx::query_as(&format!(
+            "SELECT b.year, count(*) AS count, \
+               (SELECT max(a.created_at) FROM {audit} a \
+                WHERE a.entity = $1 AND a.year = b.year) AS last_submitted \
+             FROM {table} b GROUP BY b.year ORDER BY b.year",
+            audit = AuditBmc::TABLE,
+            table = Self::TABLE,
+        ))
+        .bind(Self::TABLE)
+        .fetch_all(db)
+        .await?;
+
+        Ok(stats)
+    }
+
+    #[instrument(name = "BenchmarkBmc::get", skip(mm))]
+    pub async fn get(mm: &ModelManager, id: i32) -> Result<Benchmark> {
+        let db = mm.db();
+        let (sql, values) = Query::select()
+            .columns(BenchmarkIden::iter())
+            .from(BenchmarkIden::Table)
+            .and_where(Expr::col(BenchmarkIden::Id).eq(id))
+            .build_sqlx(PostgresQueryBuilder);
+
+        let entity = sqlx::query_as_with(&sql, values)
+            .fetch_optional(db)
+            .await?
+            .ok_or(Error::EntityNotFound {
+                table: BenchmarkBmc::TABLE,
+                id: EntityId::Id(id),
+            })?;
+
+        Ok(entity)
+    }
+}
+
+#[async_trait]
+impl UpsertBmc for BenchmarkBmc {
+    type Create = BenchmarkCreate;
+    type Entity = Benchmark;
+
+    async fn list_by_keys(
+        conn: &mut PgConnection,
+        data: &[BenchmarkCreate],
+    ) -> Result<Vec<Benchmark>> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let cond = data.iter().fold(Cond::any(), |cond, b| {
+            cond.add(
+                Cond::all()
+                    .add(Expr::col(BenchmarkIden::Year).eq(b.year))
+                    .add(Expr::col(BenchmarkIden::Day).eq(b.day))
+                    .add(Expr::col(BenchmarkIden::Input).eq(b.input.as_str()))
+                    .add(Expr::col(BenchmarkIden::Participant).eq(b.participant.as_str())),
+            )
+        });
+
+        bmc_list_on::<Self, _, _>(conn, cond).await
+    }
+
+    async fn upsert(
+        conn: &mut PgConnection,
+        data: &[BenchmarkCreate],
+    ) -> Result<Vec<(EntityId, bool)>> {
         let mut query = Query::insert();
 
         query.into_table(BenchmarkIden::Table).columns(
@@ -90,35 +216,30 @@ impl BenchmarkBmc {
                 .filter(|c| !matches!(c, BenchmarkIden::Table | BenchmarkIden::Id)),
         );
 
-        // clunky, but since we don't know the length of the iterator ahead of
-        // time and do not want to alloc to consume it
-        let mut count = 0;
-
         for benchmark_data in data {
-            query.values_panic([
-                benchmark_data.year.into(),
-                benchmark_data.day.into(),
-                benchmark_data.input.into(),
-                benchmark_data.participant.into(),
-                benchmark_data.language.into(),
-                benchmark_data.mean.into(),
-                benchmark_data.stddev.into(),
-                benchmark_data.median.into(),
-                benchmark_data.user.into(),
-                benchmark_data.system.into(),
-                benchmark_data.min.into(),
-                benchmark_data.max.into(),
-            ]);
-            count += 1;
-        }
-
-        if count == 0 {
-            return Err(Error::EmptyBatch("benchmarks"));
+            query
+                .values([
+                    benchmark_data.year.into(),
+                    benchmark_data.day.into(),
+                    benchmark_data.input.clone().into(),
+                    benchmark_data.participant.clone().into(),
+                    benchmark_data.language.clone().into(),
+                    benchmark_data.mean.into(),
+                    benchmark_data.stddev.into(),
+                    benchmark_data.median.into(),
+                    benchmark_data.user.into(),
+                    benchmark_data.system.into(),
+                    benchmark_data.min.into(),
+                    benchmark_data.max.into(),
+                ])
+                .map_err(anyhow::Error::from)?;
         }
 
         let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
         // probably not the best hack, but sea-query doesn't allow for setting
         // the ON CONSTRAINT expression.
+        //
+        // xmax is only zero for rows that were freshly inserted
         let sql = sql
             + r#" ON CONFLICT ON CONSTRAINT single_entry DO UPDATE SET
 language = excluded.language,
@@ -129,41 +250,41 @@ tuser = excluded.tuser,
 tsystem = excluded.tsystem,
 tmin = excluded.tmin,
 tmax = excluded.tmax
-RETURNING id"#;
+RETURNING id, (xmax = 0) AS inserted"#;
 
-        let ids = sqlx::query_as_with::<_, (i32,), _>(&sql, values)
-            .fetch_all(db)
+        let written = sqlx::query_as_with::<_, (i32, bool), _>(&sql, values)
+            .fetch_all(conn)
             .await?;
 
-        Ok(ids.into_iter().map(|i| i.0).collect())
+        Ok(written
+            .into_iter()
+            .map(|(id, inserted)| (EntityId::Id(id), inserted))
+            .collect())
     }
 
-    pub async fn list(mm: &ModelManager, filter: BenchmarkFilter) -> Result<Vec<Benchmark>> {
-        bmc_list::<Self, _, _>(mm, filter).await
+    fn entity_id(stored: &Benchmark) -> EntityId {
+        EntityId::Id(stored.id)
     }
 
-    pub async fn get(mm: &ModelManager, id: i32) -> Result<Benchmark> {
-        let db = mm.db();
-        let (sql, values) = Query::select()
-            .columns(BenchmarkIden::iter())
-            .from(BenchmarkIden::Table)
-            .and_where(Expr::col(BenchmarkIden::Id).eq(id))
-            .build_sqlx(PostgresQueryBuilder);
-
-        let entity = sqlx::query_as_with(&sql, values)
-            .fetch_optional(db)
-            .await?
-            .ok_or(Error::EntityNotFound {
-                table: BenchmarkBmc::TABLE,
-                id,
-            })?;
-
-        Ok(entity)
+    fn is_unchanged(data: &BenchmarkCreate, stored: &Benchmark) -> bool {
+        data.year == stored.year
+            && data.day == stored.day
+            && data.input == stored.input
+            && data.participant == stored.participant
+            && data.language == stored.language
+            && data.mean == stored.mean
+            && data.stddev == stored.stddev
+            && data.median == stored.median
+            && data.user == stored.user
+            && data.system == stored.system
+            && data.min == stored.min
+            && data.max == stored.max
     }
 }
 
-#[derive(Debug, Default, Clone, Deserialize)]
+#[derive(Debug, Default, Clone, Deserialize, IntoParams)]
 #[cfg_attr(test, derive(Serialize))]
+#[into_params(parameter_in = Query)]
 pub struct BenchmarkFilter {
     pub year: Option<i32>,
     pub day: Option<i32>,
@@ -201,8 +322,7 @@ impl From<BenchmarkFilter> for Cond {
     }
 }
 
-#[derive(Debug, Default, Clone, PartialEq, PartialOrd, Deserialize)]
-#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd, Serialize, Deserialize, ToSchema)]
 pub struct BenchmarkCreate {
     pub year: i32,
     pub day: i32,
@@ -218,6 +338,101 @@ pub struct BenchmarkCreate {
     pub max: f64,
 }
 
+impl From<Benchmark> for BenchmarkCreate {
+    fn from(value: Benchmark) -> Self {
+        Self {
+            year: value.year,
+            day: value.day,
+            input: value.input,
+            participant: value.participant,
+            language: value.language,
+            mean: value.mean,
+            stddev: value.stddev,
+            median: value.median,
+            user: value.user,
+            system: value.system,
+            min: value.min,
+            max: value.max,
+        }
+    }
+}
+
+// the unique constraint on the table
+fn benchmark_keys(year: i32, day: i32, input: &str, participant: &str) -> Value {
+    json!({
+        "year": year,
+        "day": day,
+        "input": input,
+        "participant": participant,
+    })
+}
+
+impl Auditable for Benchmark {
+    fn audit_keys(&self) -> Value {
+        benchmark_keys(self.year, self.day, &self.input, &self.participant)
+    }
+
+    fn audit_year(&self) -> i32 {
+        self.year
+    }
+
+    fn audit_participant(&self) -> &str {
+        &self.participant
+    }
+}
+
+impl Validate for BenchmarkCreate {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut v = Validator::default();
+
+        v.range("year", self.year, valid_years());
+        v.range("day", self.day, 1..=25);
+        v.string("input", &self.input, 256);
+        v.string("participant", &self.participant, 256);
+        v.string("language", &self.language, 256);
+
+        for (field, value) in [
+            ("mean", self.mean),
+            ("stddev", self.stddev),
+            ("median", self.median),
+            ("user", self.user),
+            ("system", self.system),
+            ("min", self.min),
+            ("max", self.max),
+        ] {
+            v.duration(field, value);
+        }
+
+        v.check(self.min <= self.max, "min", "must not exceed max");
+        v.check(
+            self.min <= self.median && self.median <= self.max,
+            "median",
+            "must be between min and max",
+        );
+        v.check(
+            self.min <= self.mean && self.mean <= self.max,
+            "mean",
+            "must be between min and max",
+        );
+
+        v.finish()
+    }
+}
+
+impl Auditable for BenchmarkCreate {
+    fn audit_keys(&self) -> Value {
+        benchmark_keys(self.year, self.day, &self.input, &self.participant)
+    }
+
+    fn audit_year(&self) -> i32 {
+        self.year
+    }
+
+    fn audit_participant(&self) -> &str {
+        &self.participant
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::f64::EPSILON;
@@ -225,6 +440,7 @@ mod tests {
     use sqlx::PgPool;
 
     use super::*;
+    use crate::models::{outcome::OutcomeStatus, AuditContext, EntityId};
 
     // we need to do this to check the floating point values
     macro_rules! assert_benchmarks_equal {
@@ -264,8 +480,10 @@ mod tests {
             max: 0.4558,
         };
 
-        let id = BenchmarkBmc::create_or_update(&mm, entry).await?;
-        assert_eq!(id, 1000);
+        let outcome =
+            BenchmarkBmc::create_or_update(&mm, &AuditContext::new("test", "test"), entry).await?;
+        assert_eq!(outcome.status, OutcomeStatus::Created);
+        assert_eq!(outcome.id, Some(EntityId::Id(1000)));
 
         // insert a conflicting row and a new row
         let data = vec![
@@ -299,10 +517,23 @@ mod tests {
             },
         ];
 
-        let ids = BenchmarkBmc::batch_create_or_update(&mm, data.clone()).await?;
+        let outcomes = BenchmarkBmc::batch_create_or_update(
+            &mm,
+            &AuditContext::new("test", "test"),
+            data.clone(),
+        )
+        .await?;
+        let ids: Vec<_> = outcomes.iter().map(|o| o.id.clone()).collect();
         // we leave a gap because of the way the insert works in bulk with a
         // conflict
-        assert_eq!(ids, vec![1000, 1002]);
+        assert_eq!(
+            ids,
+            vec![Some(EntityId::Id(1000)), Some(EntityId::Id(1002))]
+        );
+        assert_eq!(outcomes[0].status, OutcomeStatus::Updated);
+        assert_eq!(outcomes[0].previous.as_ref().unwrap().mean, 0.24277);
+        assert_eq!(outcomes[1].status, OutcomeStatus::Created);
+        assert_eq!(outcomes[1].previous, None);
 
         let all = BenchmarkBmc::list(&mm, BenchmarkFilter::default()).await?;
 
@@ -316,13 +547,101 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test(fixtures("../../fixtures/benchmarks.sql"))]
+    async fn test_batch_create_or_update_partial_ok(pool: PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+
+        let existing = BenchmarkBmc::get(&mm, 1000).await?;
+        let unchanged = BenchmarkCreate {
+            year: existing.year,
+            day: existing.day,
+            input: existing.input.clone(),
+            participant: existing.participant.clone(),
+            language: existing.language.clone(),
+            mean: existing.mean,
+            stddev: existing.stddev,
+            median: existing.median,
+            user: existing.user,
+            system: existing.system,
+            min: existing.min,
+            max: existing.max,
+        };
+
+        let updated = BenchmarkCreate {
+            language: "zig".into(),
+            ..unchanged.clone()
+        };
+
+        let created = BenchmarkCreate {
+            day: 20,
+            ..unchanged.clone()
+        };
+
+        let invalid = BenchmarkCreate {
+            day: 30,
+            ..unchanged.clone()
+        };
+
+        let data = vec![
+            unchanged,
+            created.clone(),
+            invalid,
+            created.clone(),
+            BenchmarkCreate {
+                input: "other".into(),
+                ..updated.clone()
+            },
+        ];
+
+        let outcomes = BenchmarkBmc::batch_create_or_update_partial(
+            &mm,
+            &AuditContext::new("test", "test"),
+            data,
+        )
+        .await?;
+
+        let statuses: Vec<_> = outcomes.iter().map(|o| o.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                OutcomeStatus::Unchanged,
+                OutcomeStatus::Created,
+                OutcomeStatus::Rejected,
+                OutcomeStatus::Rejected,
+                OutcomeStatus::Created,
+            ]
+        );
+        assert_eq!(outcomes[0].id, Some(EntityId::Id(1000)));
+        assert_eq!(outcomes[2].errors[0].field, "day");
+        assert_eq!(outcomes[3].reason.as_deref(), Some("duplicate"));
+        assert_eq!(outcomes[3].errors[0].message, "duplicates record 1");
+
+        // now actually change the existing one
+        let outcomes = BenchmarkBmc::batch_create_or_update_partial(
+            &mm,
+            &AuditContext::new("test", "test"),
+            [updated],
+        )
+        .await?;
+        assert_eq!(outcomes[0].status, OutcomeStatus::Updated);
+        assert_eq!(outcomes[0].id, Some(EntityId::Id(1000)));
+        assert_eq!(outcomes[0].previous, Some(existing));
+
+        let stored = BenchmarkBmc::get(&mm, 1000).await?;
+        assert_eq!(stored.language, "zig");
+
+        Ok(())
+    }
+
     #[sqlx::test]
     async fn test_batch_create_empty_is_err(pool: PgPool) -> anyhow::Result<()> {
         let mm = ModelManager::from(pool);
 
         let data = Vec::new();
 
-        let res = BenchmarkBmc::batch_create_or_update(&mm, data).await;
+        let res =
+            BenchmarkBmc::batch_create_or_update(&mm, &AuditContext::new("test", "test"), data)
+                .await;
 
         assert!(matches!(res, Err(Error::EmptyBatch("benchmarks"))));
 
@@ -366,6 +685,40 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test(fixtures("../../fixtures/benchmarks.sql"))]
+    async fn test_stats_ok(pool: PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+
+        let stats = BenchmarkBmc::stats(&mm).await?;
+        assert_eq!(
+            stats,
+            [YearStats {
+                year: 2023,
+                count: 4,
+                last_submitted: None,
+            }]
+        );
+
+        let data = [BenchmarkCreate {
+            year: 2022,
+            day: 1,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            ..Default::default()
+        }];
+        let ctx = AuditContext::new("test", "POST /");
+        BenchmarkBmc::batch_create_or_update(&mm, &ctx, data).await?;
+
+        let stats = BenchmarkBmc::stats(&mm).await?;
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].year, stats[0].count), (2022, 1));
+        assert!(stats[0].last_submitted.is_some());
+        assert!(stats[1].last_submitted.is_none());
+
+        Ok(())
+    }
+
     #[sqlx::test(fixtures("../../fixtures/benchmarks.sql"))]
     async fn test_get_ok(pool: PgPool) -> anyhow::Result<()> {
         let mm = ModelManager::from(pool);
@@ -385,7 +738,7 @@ mod tests {
                 res,
                 Err(Error::EntityNotFound {
                     table: BenchmarkBmc::TABLE,
-                    id: 1000,
+                    id: EntityId::Id(1000),
                 })
             ),
             "EntityNotFound does not match"
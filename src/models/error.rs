@@ -1,11 +1,16 @@
-use super::store;
+use std::fmt;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::{store, validation::ValidationErrors};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("{table} not found: {id}")]
-    EntityNotFound { table: &'static str, id: i32 },
+    EntityNotFound { table: &'static str, id: EntityId },
 
     #[error("Day out of range: {0}")]
     DayOutOfRange(i32),
@@ -13,6 +18,9 @@ pub enum Error {
     #[error("Empty batch for {0}")]
     EmptyBatch(&'static str),
 
+    #[error("Validation failed: {0}")]
+    Validation(ValidationErrors),
+
     #[error(transparent)]
     Store(#[from] store::Error),
 
@@ -25,3 +33,25 @@ pub enum Error {
     #[error(transparent)]
     Unhandled(#[from] anyhow::Error),
 }
+
+/// The key an entity was looked up by, which may be composite depending on the
+/// table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EntityId {
+    Id(i32),
+    Participant { year: i32, name: String },
+    Summary { year: i32, participant: String },
+}
+
+impl fmt::Display for EntityId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Participant { year, name } => write!(f, "year={year}, name={name}"),
+            Self::Summary { year, participant } => {
+                write!(f, "year={year}, participant={participant}")
+            }
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::time::Duration;
+
+use sea_query::{Alias, Cond, Expr, Iden, OnConflict, PostgresQueryBuilder, Query};
+use sea_query_binder::SqlxBinder;
+use sqlx::FromRow;
+use strum::EnumIter;
+use time::OffsetDateTime;
+use tracing::instrument;
+
+use super::{base::DbBmc, ModelManager, Result};
+
+/// Identifies a single request made with an idempotency key.
+///
+/// Keys are scoped to the route they were used with, and the hash of the
+/// request lets us detect a key being reused for a different request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyKey {
+    pub key: String,
+    pub route: String,
+    pub request_hash: String,
+}
+
+/// A response as it was originally sent.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct StoredResponse {
+    pub status: i16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// The result of attempting to claim a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// The key was not in use, and the caller is now responsible for
+    /// completing or releasing it.
+    Acquired,
+    /// The original request is still being handled.
+    InProgress,
+    /// The key was used with a different request.
+    Mismatch,
+    /// The original request completed with this response.
+    Completed(StoredResponse),
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct ClaimRow {
+    request_hash: String,
+    status: Option<i16>,
+    content_type: Option<String>,
+    body: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, Iden, EnumIter)]
+pub enum IdempotencyIden {
+    #[iden = "idempotency_keys"]
+    Table,
+    Key,
+    Route,
+    RequestHash,
+    CreatedAt,
+    Status,
+    ContentType,
+    Body,
+}
+
+pub struct IdempotencyBmc;
+
+impl DbBmc for IdempotencyBmc {
+    const TABLE: &'static str = "idempotency_keys";
+    type Iden = IdempotencyIden;
+
+    fn table_iden() -> Self::Iden {
+        Self::Iden::Table
+    }
+}
+
+impl IdempotencyBmc {
+    /// Attempt to claim `key`, ignoring anything older than `window`.
+    ///
+    /// A key that is still in progress after `lease` is assumed to have been
+    /// abandoned, and can be taken over by a retry of the same request.
+    #[instrument(name = "IdempotencyBmc::claim", skip_all)]
+    pub async fn claim(
+        mm: &ModelManager,
+        key: &IdempotencyKey,
+        window: Duration,
+        lease: Duration,
+    ) -> Result<Claim> {
+        let db = mm.db();
+
+        let now = OffsetDateTime::now_utc();
+        let created_at = Expr::col((IdempotencyIden::Table, IdempotencyIden::CreatedAt));
+        let takeover = Cond::any().add(created_at.clone().lt(now - window)).add(
+            Cond::all()
+                .add(Expr::col((IdempotencyIden::Table, IdempotencyIden::Status)).is_null())
+                .add(created_at.lt(now - lease))
+                .add(
+                    Expr::col((IdempotencyIden::Table, IdempotencyIden::RequestHash))
+                        .equals((Alias::new("excluded"), IdempotencyIden::RequestHash)),
+                ),
+        );
+
+        let (sql, values) = Query::insert()
+            .into_table(IdempotencyIden::Table)
+            .columns([
+                IdempotencyIden::Key,
+                IdempotencyIden::Route,
+                IdempotencyIden::RequestHash,
+            ])
+            .values_panic([
+                key.key.clone().into(),
+                key.route.clone().into(),
+                key.request_hash.clone().into(),
+            ])
+            // an expired or abandoned key is reset as if it was new
+            .on_conflict(
+                OnConflict::columns([IdempotencyIden::Key, IdempotencyIden::Route])
+                    .update_columns([
+                        IdempotencyIden::RequestHash,
+                        IdempotencyIden::CreatedAt,
+                        IdempotencyIden::Status,
+                        IdempotencyIden::ContentType,
+                        IdempotencyIden::Body,
+                    ])
+                    .action_cond_where(takeover)
+                    .to_owned(),
+            )
+            .returning_col(IdempotencyIden::Key)
+            .build_sqlx(PostgresQueryBuilder);
+
+        let inserted = sqlx::query_with(&sql, values).fetch_optional(db).await?;
+
+        if inserted.is_some() {
+            return Ok(Claim::Acquired);
+        }
+
+        let (sql, values) = Query::select()
+            .columns([
+                IdempotencyIden::RequestHash,
+                IdempotencyIden::Status,
+                IdempotencyIden::ContentType,
+                IdempotencyIden::Body,
+            ])
+            .from(IdempotencyIden::Table)
+            .and_where(Expr::col(IdempotencyIden::Key).eq(key.key.clone()))
+            .and_where(Expr::col(IdempotencyIden::Route).eq(key.route.clone()))
+            .build_sqlx(PostgresQueryBuilder);
+
+        let existing: Option<ClaimRow> =
+            sqlx::query_as_with(&sql, values).fetch_optional(db).await?;
+
+        // if the row vanished between the two queries the original request was
+        // released, which we treat the same as it still being in flight
+        let claim = match existing {
+            Some(row) if row.request_hash != key.request_hash => Claim::Mismatch,
+            Some(ClaimRow {
+                status: Some(status),
+                content_type,
+                body,
+                ..
+            }) => Claim::Completed(StoredResponse {
+                status,
+                content_type,
+                body: body.unwrap_or_default(),
+            }),
+            _ => Claim::InProgress,
+        };
+
+        Ok(claim)
+    }
+
+    /// Store the response for a previously acquired key.
+    #[instrument(name = "IdempotencyBmc::complete", skip_all)]
+    pub async fn complete(
+        mm: &ModelManager,
+        key: &IdempotencyKey,
+        response: &StoredResponse,
+    ) -> Result<()> {
+        let db = mm.db();
+
+        let (sql, values) = Query::update()
+            .table(IdempotencyIden::Table)
+            .values([
+                (IdempotencyIden::Status, response.status.into()),
+                (
+                    IdempotencyIden::ContentType,
+                    response.content_type.clone().into(),
+                ),
+                (IdempotencyIden::Body, response.body.clone().into()),
+            ])
+            .and_where(Expr::col(IdempotencyIden::Key).eq(key.key.clone()))
+            .and_where(Expr::col(IdempotencyIden::Route).eq(key.route.clone()))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values).execute(db).await?;
+
+        Ok(())
+    }
+
+    /// Give up a previously acquired key so it can be retried.
+    #[instrument(name = "IdempotencyBmc::release", skip_all)]
+    pub async fn release(mm: &ModelManager, key: &IdempotencyKey) -> Result<()> {
+        let db = mm.db();
+
+        let (sql, values) = Query::delete()
+            .from_table(IdempotencyIden::Table)
+            .and_where(Expr::col(IdempotencyIden::Key).eq(key.key.clone()))
+            .and_where(Expr::col(IdempotencyIden::Route).eq(key.route.clone()))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values).execute(db).await?;
+
+        Ok(())
+    }
+
+    /// Remove every key older than `window`, returning how many were removed.
+    #[instrument(name = "IdempotencyBmc::purge_expired", skip_all)]
+    pub async fn purge_expired(mm: &ModelManager, window: Duration) -> Result<u64> {
+        let db = mm.db();
+
+        let cutoff = OffsetDateTime::now_utc() - window;
+
+        let (sql, values) = Query::delete()
+            .from_table(IdempotencyIden::Table)
+            .and_where(Expr::col(IdempotencyIden::CreatedAt).lt(cutoff))
+            .build_sqlx(PostgresQueryBuilder);
+
+        let res = sqlx::query_with(&sql, values).execute(db).await?;
+
+        Ok(res.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(request_hash: &str) -> IdempotencyKey {
+        IdempotencyKey {
+            key: "abc".into(),
+            route: "POST /api/v1/benchmarks".into(),
+            request_hash: request_hash.repeat(64),
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_claim_lifecycle(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let window = Duration::from_secs(60);
+        let lease = Duration::from_secs(60);
+
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("a"), window, lease).await?,
+            Claim::Acquired
+        );
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("a"), window, lease).await?,
+            Claim::InProgress
+        );
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("b"), window, lease).await?,
+            Claim::Mismatch
+        );
+
+        let response = StoredResponse {
+            status: 200,
+            content_type: Some("application/json".into()),
+            body: b"[]".to_vec(),
+        };
+        IdempotencyBmc::complete(&mm, &key("a"), &response).await?;
+
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("a"), window, lease).await?,
+            Claim::Completed(response)
+        );
+
+        // the same key on a different route is unrelated
+        let other = IdempotencyKey {
+            route: "POST /api/v1/participants".into(),
+            ..key("b")
+        };
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &other, window, lease).await?,
+            Claim::Acquired
+        );
+
+        IdempotencyBmc::release(&mm, &other).await?;
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &other, window, lease).await?,
+            Claim::Acquired
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_claim_expired(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let minute = Duration::from_secs(60);
+
+        IdempotencyBmc::claim(&mm, &key("a"), minute, minute).await?;
+
+        // with no window at all, everything is already expired
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("b"), Duration::ZERO, minute).await?,
+            Claim::Acquired
+        );
+        assert_eq!(IdempotencyBmc::purge_expired(&mm, Duration::ZERO).await?, 1);
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("b"), minute, minute).await?,
+            Claim::Acquired
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_claim_abandoned(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let minute = Duration::from_secs(60);
+
+        IdempotencyBmc::claim(&mm, &key("a"), minute, minute).await?;
+
+        // only a retry of the same request can take over
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("b"), minute, Duration::ZERO).await?,
+            Claim::Mismatch
+        );
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("a"), minute, Duration::ZERO).await?,
+            Claim::Acquired
+        );
+
+        // completed requests are kept for the whole window
+        let response = StoredResponse {
+            status: 200,
+            content_type: None,
+            body: Vec::new(),
+        };
+        IdempotencyBmc::complete(&mm, &key("a"), &response).await?;
+        assert_eq!(
+            IdempotencyBmc::claim(&mm, &key("a"), minute, Duration::ZERO).await?,
+            Claim::Completed(response)
+        );
+
+        Ok(())
+    }
+}
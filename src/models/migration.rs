@@ -0,0 +1,156 @@
+use std::{collections::HashMap, time::Duration};
+
+use sqlx::{migrate::Migrator, FromRow};
+use strum::AsRefStr;
+use time::OffsetDateTime;
+
+use super::{ModelManager, Result};
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but did not complete successfully.
+    Failed,
+    /// Applied, but the embedded migration has since been modified.
+    ChecksumMismatch,
+    /// Applied, but not known to this version of the application.
+    Unknown,
+}
+
+/// How a single migration relates to the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+    pub installed_on: Option<OffsetDateTime>,
+    pub execution_time: Option<Duration>,
+}
+
+// mirrors the table sqlx maintains
+#[derive(Debug, Clone, FromRow)]
+struct AppliedRow {
+    version: i64,
+    description: String,
+    installed_on: OffsetDateTime,
+    success: bool,
+    checksum: Vec<u8>,
+    execution_time: i64,
+}
+
+pub(in crate::models) async fn run(mm: &ModelManager) -> Result<()> {
+    MIGRATOR.run(mm.db()).await?;
+
+    Ok(())
+}
+
+/// Compare the embedded migrations against what has been applied, ordered by
+/// version.
+pub(in crate::models) async fn status(mm: &ModelManager) -> Result<Vec<MigrationStatus>> {
+    let db = mm.db();
+
+    // we don't want to create the table just to look at it, as the user may
+    // not have the rights to do so
+    let (exists,): (bool,) = sqlx::query_as("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(db)
+        .await?;
+
+    let applied: Vec<AppliedRow> = if exists {
+        sqlx::query_as(
+            "SELECT version, description, installed_on, success, checksum, execution_time \
+             FROM _sqlx_migrations ORDER BY version",
+        )
+        .fetch_all(db)
+        .await?
+    } else {
+        Vec::new()
+    };
+
+    let mut applied: HashMap<i64, AppliedRow> =
+        applied.into_iter().map(|row| (row.version, row)).collect();
+
+    let mut statuses: Vec<_> = MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|migration| match applied.remove(&migration.version) {
+            Some(row) => {
+                let state = if !row.success {
+                    MigrationState::Failed
+                } else if row.checksum != *migration.checksum {
+                    MigrationState::ChecksumMismatch
+                } else {
+                    MigrationState::Applied
+                };
+
+                MigrationStatus {
+                    version: migration.version,
+                    description: migration.description.to_string(),
+                    state,
+                    installed_on: Some(row.installed_on),
+                    execution_time: Some(Duration::from_nanos(row.execution_time as u64)),
+                }
+            }
+            None => MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state: MigrationState::Pending,
+                installed_on: None,
+                execution_time: None,
+            },
+        })
+        .collect();
+
+    statuses.extend(applied.into_values().map(|row| MigrationStatus {
+        version: row.version,
+        description: row.description,
+        state: MigrationState::Unknown,
+        installed_on: Some(row.installed_on),
+        execution_time: Some(Duration::from_nanos(row.execution_time as u64)),
+    }));
+
+    statuses.sort_by_key(|s| s.version);
+
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn test_status_applied(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+
+        let statuses = status(&mm).await?;
+
+        assert!(!statuses.is_empty());
+        assert!(statuses
+            .iter()
+            .all(|s| s.state == MigrationState::Applied && s.installed_on.is_some()));
+        assert_eq!(statuses[0].description, "base");
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_status_pending_then_run(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+
+        let statuses = status(&mm).await?;
+        assert!(statuses
+            .iter()
+            .all(|s| s.state == MigrationState::Pending && s.installed_on.is_none()));
+
+        run(&mm).await?;
+
+        let after = status(&mm).await?;
+        assert_eq!(after.len(), statuses.len());
+        assert!(after.iter().all(|s| s.state == MigrationState::Applied));
+
+        Ok(())
+    }
+}
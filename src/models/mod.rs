@@ -1,17 +1,27 @@
+mod audit;
 mod base;
 mod benchmark;
 mod error;
+mod idempotency;
+mod migration;
+mod outcome;
 mod participant;
 mod store;
 mod summary;
+mod validation;
 
+pub use self::audit::{AuditBmc, AuditContext, AuditEntry, AuditFilter};
 pub use self::benchmark::{Benchmark, BenchmarkBmc, BenchmarkCreate, BenchmarkFilter};
-pub use self::error::{Error, Result};
+pub use self::error::{EntityId, Error, Result};
+pub use self::idempotency::{Claim, IdempotencyBmc, IdempotencyKey, StoredResponse};
+pub use self::migration::{MigrationState, MigrationStatus};
+pub use self::outcome::{BatchOutcome, OutcomeStatus};
 pub use self::participant::{Participant, ParticipantBmc, ParticipantFilter};
 pub use self::summary::{Summary, SummaryBmc, SummaryFilter};
 
 // do not expose above the model layer
 use self::store::{new_db_pool, Db};
+use crate::config;
 
 #[derive(Debug, Clone)]
 pub struct ModelManager {
@@ -19,8 +29,8 @@ pub struct ModelManager {
 }
 
 impl ModelManager {
-    pub async fn new() -> Result<Self> {
-        let db = new_db_pool().await?;
+    pub async fn new(config: &config::Db) -> Result<Self> {
+        let db = new_db_pool(config).await?;
 
         Ok(ModelManager { db })
     }
@@ -35,9 +45,26 @@ impl ModelManager {
     /// This is probably the wrong place for this, but we don't allow the db
     /// pool to leak beyond the manager.
     pub async fn migrate(&self) -> Result<()> {
-        sqlx::migrate!().run(self.db()).await?;
+        migration::run(self).await
+    }
 
-        Ok(())
+    /// Every known migration and whether or not it has been applied.
+    pub async fn migration_status(&self) -> Result<Vec<MigrationStatus>> {
+        migration::status(self).await
+    }
+
+    /// How busy the connection pool is right now.
+    pub fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.db.size(),
+            idle: self.db.num_idle(),
+            max: self.db.options().get_max_connections(),
+        }
+    }
+
+    /// Wait for the connections in use to be returned, then close them all.
+    pub async fn close(&self) {
+        self.db.close().await;
     }
 
     pub(in crate::models) fn db(&self) -> &Db {
@@ -45,6 +72,14 @@ impl ModelManager {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Open connections, both idle and in use.
+    pub size: u32,
+    pub idle: usize,
+    pub max: u32,
+}
+
 #[cfg(test)]
 impl From<Db> for ModelManager {
     fn from(db: Db) -> Self {
@@ -0,0 +1,74 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::{validation::FieldError, EntityId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema, strum::AsRefStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum OutcomeStatus {
+    Created,
+    Updated,
+    Unchanged,
+    Rejected,
+}
+
+/// What happened to the record at `index` of a submitted batch.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct BatchOutcome<T> {
+    pub index: usize,
+    pub status: OutcomeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<EntityId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// The stored version of the record prior to an update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous: Option<T>,
+}
+
+impl<T> BatchOutcome<T> {
+    pub fn rejected(index: usize, reason: impl Into<String>, errors: Vec<FieldError>) -> Self {
+        Self {
+            index,
+            status: OutcomeStatus::Rejected,
+            id: None,
+            reason: Some(reason.into()),
+            errors,
+            previous: None,
+        }
+    }
+
+    pub fn written(index: usize, id: EntityId, inserted: bool, previous: Option<T>) -> Self {
+        Self {
+            index,
+            status: if inserted {
+                OutcomeStatus::Created
+            } else {
+                OutcomeStatus::Updated
+            },
+            id: Some(id),
+            reason: None,
+            errors: Vec::new(),
+            previous,
+        }
+    }
+
+    pub fn unchanged(index: usize, id: EntityId) -> Self {
+        Self {
+            index,
+            status: OutcomeStatus::Unchanged,
+            id: Some(id),
+            reason: None,
+            errors: Vec::new(),
+            previous: None,
+        }
+    }
+
+    /// If this record was actually written to the db.
+    pub fn is_write(&self) -> bool {
+        matches!(self.status, OutcomeStatus::Created | OutcomeStatus::Updated)
+    }
+}
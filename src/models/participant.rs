@@ -1,16 +1,36 @@
+use async_trait::async_trait;
+use futures::stream::BoxStream;
 use sea_query::{Cond, Expr, Iden, PostgresQueryBuilder, Query};
 use sea_query_binder::SqlxBinder;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use serde_json::{json, Value};
+use sqlx::{FromRow, PgConnection};
 use strum::{EnumIter, IntoEnumIterator};
+use tracing::instrument;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
 
 use super::{
-    base::{bmc_list, DbBmc},
-    Error, ModelManager, Result,
+    audit::{AuditContext, Auditable},
+    base::{bmc_list, bmc_list_on, bmc_stream, bmc_upsert, DbBmc, UpsertBmc},
+    outcome::BatchOutcome,
+    validation::{valid_years, FieldError, Validate, Validator},
+    EntityId, Error, ModelManager, Result,
 };
 
 #[derive(
-    Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, FromRow, Serialize, Deserialize,
+    Debug,
+    Default,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    FromRow,
+    Serialize,
+    Deserialize,
+    ToSchema,
 )]
 pub struct Participant {
     pub year: i32,
@@ -19,6 +39,40 @@ pub struct Participant {
     pub repo: String,
 }
 
+impl Auditable for Participant {
+    fn audit_keys(&self) -> Value {
+        json!({"year": self.year, "name": self.name})
+    }
+
+    fn audit_year(&self) -> i32 {
+        self.year
+    }
+
+    fn audit_participant(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Validate for Participant {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut v = Validator::default();
+
+        v.range("year", self.year, valid_years());
+        v.string("name", &self.name, 256);
+        v.string("language", &self.language, 256);
+        v.string("repo", &self.repo, 512);
+        v.check(
+            Url::parse(&self.repo)
+                .map(|u| matches!(u.scheme(), "http" | "https"))
+                .unwrap_or(false),
+            "repo",
+            "must be an http(s) url",
+        );
+
+        v.finish()
+    }
+}
+
 // this sucks, but we have to wait for a newer version of sea-query to allow
 // more control over the struct proc macro
 #[derive(Debug, Clone, Copy, Iden, EnumIter)]
@@ -43,15 +97,85 @@ impl DbBmc for ParticipantBmc {
 }
 
 impl ParticipantBmc {
+    /// Create new Participants or update existing ones on a collision,
+    /// reporting what happened to each.
     pub async fn batch_create_or_update<Iter>(
         mm: &ModelManager,
+        ctx: &AuditContext,
         data: Iter,
-    ) -> Result<Vec<(i32, String)>>
+    ) -> Result<Vec<BatchOutcome<Participant>>>
     where
         Iter: IntoIterator<Item = Participant>,
     {
+        bmc_upsert::<Self>(mm, ctx, data.into_iter().collect(), false).await
+    }
+
+    pub async fn list(mm: &ModelManager, filter: ParticipantFilter) -> Result<Vec<Participant>> {
+        bmc_list::<Self, _, _>(mm, filter).await
+    }
+
+    /// Stream every participant matching `filter`, for results too large to list.
+    pub fn stream(
+        mm: &ModelManager,
+        filter: ParticipantFilter,
+    ) -> BoxStream<'static, Result<Participant>> {
+        bmc_stream::<Self, _, _>(mm, filter)
+    }
+
+    #[instrument(name = "ParticipantBmc::get", skip(mm))]
+    pub async fn get(mm: &ModelManager, year: i32, name: &str) -> Result<Participant> {
         let db = mm.db();
 
+        let (sql, values) = Query::select()
+            .columns(ParticipantIden::iter())
+            .from(ParticipantIden::Table)
+            .and_where(Expr::col(ParticipantIden::Year).eq(year))
+            .and_where(Expr::col(ParticipantIden::Name).eq(name))
+            .build_sqlx(PostgresQueryBuilder);
+
+        let entity = sqlx::query_as_with(&sql, values)
+            .fetch_optional(db)
+            .await?
+            .ok_or_else(|| Error::EntityNotFound {
+                table: ParticipantBmc::TABLE,
+                id: EntityId::Participant {
+                    year,
+                    name: name.to_string(),
+                },
+            })?;
+
+        Ok(entity)
+    }
+}
+
+#[async_trait]
+impl UpsertBmc for ParticipantBmc {
+    type Create = Participant;
+    type Entity = Participant;
+
+    async fn list_by_keys(
+        conn: &mut PgConnection,
+        data: &[Participant],
+    ) -> Result<Vec<Participant>> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let cond = data.iter().fold(Cond::any(), |cond, p| {
+            cond.add(
+                Cond::all()
+                    .add(Expr::col(ParticipantIden::Year).eq(p.year))
+                    .add(Expr::col(ParticipantIden::Name).eq(p.name.as_str())),
+            )
+        });
+
+        bmc_list_on::<Self, _, _>(conn, cond).await
+    }
+
+    async fn upsert(
+        conn: &mut PgConnection,
+        data: &[Participant],
+    ) -> Result<Vec<(EntityId, bool)>> {
         let mut query = Query::insert();
 
         query
@@ -59,59 +183,53 @@ impl ParticipantBmc {
             .columns(ParticipantIden::iter().filter(|v| !matches!(v, ParticipantIden::Table)));
 
         for participant_data in data {
-            query.values_panic([
-                participant_data.year.into(),
-                participant_data.name.into(),
-                participant_data.language.into(),
-                participant_data.repo.into(),
-            ]);
+            query
+                .values([
+                    participant_data.year.into(),
+                    participant_data.name.clone().into(),
+                    participant_data.language.clone().into(),
+                    participant_data.repo.clone().into(),
+                ])
+                .map_err(anyhow::Error::from)?;
         }
 
         let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
 
         // probably not the best hack, but sea-query doesn't allow for setting
         // the ON CONSTRAINT expression.
+        //
+        // xmax is only zero for rows that were freshly inserted
         let sql = sql
             + r#" ON CONFLICT (year, name) DO UPDATE SET
 language = excluded.language,
 repo = excluded.repo
-RETURNING year, name"#;
+RETURNING year, name, (xmax = 0) AS inserted"#;
 
-        let ids = sqlx::query_as_with::<_, (i32, String), _>(&sql, values)
-            .fetch_all(db)
+        let written = sqlx::query_as_with::<_, (i32, String, bool), _>(&sql, values)
+            .fetch_all(conn)
             .await?;
 
-        Ok(ids)
+        Ok(written
+            .into_iter()
+            .map(|(year, name, inserted)| (EntityId::Participant { year, name }, inserted))
+            .collect())
     }
 
-    pub async fn list(mm: &ModelManager, filter: ParticipantFilter) -> Result<Vec<Participant>> {
-        bmc_list::<Self, _, _>(mm, filter).await
+    fn entity_id(stored: &Participant) -> EntityId {
+        EntityId::Participant {
+            year: stored.year,
+            name: stored.name.clone(),
+        }
     }
 
-    pub async fn get(mm: &ModelManager, year: i32, name: &str) -> Result<Participant> {
-        let db = mm.db();
-
-        let (sql, values) = Query::select()
-            .columns(ParticipantIden::iter())
-            .from(ParticipantIden::Table)
-            .and_where(Expr::col(ParticipantIden::Year).eq(year))
-            .and_where(Expr::col(ParticipantIden::Name).eq(name))
-            .build_sqlx(PostgresQueryBuilder);
-
-        let entity = sqlx::query_as_with(&sql, values)
-            .fetch_optional(db)
-            .await?
-            .ok_or(Error::EntityNotFound {
-                table: ParticipantBmc::TABLE,
-                id: year,
-            })?;
-
-        Ok(entity)
+    fn is_unchanged(data: &Participant, stored: &Participant) -> bool {
+        data == stored
     }
 }
 
-#[derive(Debug, Default, Clone, Deserialize)]
+#[derive(Debug, Default, Clone, Deserialize, IntoParams)]
 #[cfg_attr(test, derive(Serialize))]
+#[into_params(parameter_in = Query)]
 pub struct ParticipantFilter {
     pub year: Option<i32>,
     pub name: Option<String>,
@@ -142,6 +260,14 @@ impl From<ParticipantFilter> for Cond {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{outcome::OutcomeStatus, AuditContext, EntityId};
+
+    fn participant_id(year: i32, name: &str) -> Option<EntityId> {
+        Some(EntityId::Participant {
+            year,
+            name: name.into(),
+        })
+    }
 
     #[sqlx::test]
     async fn test_create_batch_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
@@ -169,16 +295,23 @@ mod tests {
             },
         ];
 
-        let ids = ParticipantBmc::batch_create_or_update(&mm, data.clone()).await?;
+        let outcomes = ParticipantBmc::batch_create_or_update(
+            &mm,
+            &AuditContext::new("test", "test"),
+            data.clone(),
+        )
+        .await?;
+        let ids: Vec<_> = outcomes.iter().map(|o| o.id.clone()).collect();
 
         assert_eq!(
             ids,
             vec![
-                (2023, "foo".to_string()),
-                (2023, "bar".to_string()),
-                (2022, "foo".to_string()),
+                participant_id(2023, "foo"),
+                participant_id(2023, "bar"),
+                participant_id(2022, "foo"),
             ]
         );
+        assert!(outcomes.iter().all(|o| o.status == OutcomeStatus::Created));
 
         let participants = ParticipantBmc::list(&mm, ParticipantFilter::default()).await?;
 
@@ -203,12 +336,24 @@ mod tests {
             },
         ];
 
-        let ids = ParticipantBmc::batch_create_or_update(&mm, data.clone()).await?;
+        let outcomes = ParticipantBmc::batch_create_or_update(
+            &mm,
+            &AuditContext::new("test", "test"),
+            data.clone(),
+        )
+        .await?;
+        let ids: Vec<_> = outcomes.iter().map(|o| o.id.clone()).collect();
 
         assert_eq!(
             ids,
-            vec![(2023, "foo".to_string()), (2022, "bar".to_string()),]
+            vec![participant_id(2023, "foo"), participant_id(2022, "bar")]
+        );
+        assert_eq!(outcomes[0].status, OutcomeStatus::Updated);
+        assert_eq!(
+            outcomes[0].previous.as_ref().map(|p| p.repo.as_str()),
+            Some("https://foobar/foo2")
         );
+        assert_eq!(outcomes[1].status, OutcomeStatus::Created);
 
         let mut participants = ParticipantBmc::list(&mm, ParticipantFilter::default()).await?;
         participants.sort_by(|a, b| a.year.cmp(&b.year).then_with(|| a.name.cmp(&b.name)));
@@ -219,6 +364,31 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test]
+    async fn test_create_batch_invalid(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+
+        let data = vec![Participant {
+            year: 2023,
+            name: "a".repeat(257),
+            language: "ruby".into(),
+            repo: "not a url".into(),
+        }];
+
+        let res =
+            ParticipantBmc::batch_create_or_update(&mm, &AuditContext::new("test", "test"), data)
+                .await;
+
+        let Err(Error::Validation(errors)) = res else {
+            panic!("expected validation error");
+        };
+
+        let fields: Vec<_> = errors.0[0].fields.iter().map(|f| f.field).collect();
+        assert_eq!(fields, vec!["name", "repo"]);
+
+        Ok(())
+    }
+
     #[sqlx::test(fixtures("../../fixtures/participants.sql"))]
     async fn test_list_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
         let mm = ModelManager::from(pool);
@@ -258,4 +428,33 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn test_concurrent_upserts_are_audited_in_order(
+        pool: sqlx::PgPool,
+    ) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let ctx = AuditContext::new("test", "test");
+
+        let upserts = (0..8).map(|i| {
+            ParticipantBmc::batch_create_or_update(
+                &mm,
+                &ctx,
+                [Participant {
+                    year: 2023,
+                    name: "foo".into(),
+                    language: "rust".into(),
+                    repo: format!("https://foobar/foo{i}"),
+                }],
+            )
+        });
+        futures::future::try_join_all(upserts).await?;
+
+        // every write saw the one before it, so only the first is a create
+        let entries = crate::models::AuditBmc::list(&mm, Default::default()).await?;
+        assert_eq!(entries.len(), 8);
+        assert_eq!(entries.iter().filter(|e| e.action == "create").count(), 1);
+
+        Ok(())
+    }
 }
@@ -10,16 +10,10 @@ pub use self::error::{Error, Result};
 pub type Db = Pool<Postgres>;
 
 #[instrument(err(Debug))]
-pub async fn new_db_pool() -> Result<Db> {
-    let max_connections = if cfg!(test) {
-        1
-    } else {
-        config().db.max_connections
-    };
-
+pub async fn new_db_pool(config: &config::Db) -> Result<Db> {
     PgPoolOptions::new()
-        .max_connections(max_connections)
-        .connect(config().db.url().as_str())
+        .max_connections(config.max_connections)
+        .connect(config.url().as_str())
         .await
         .map_err(|e| Error::FailedToCreatePool(e.to_string()))
 }
@@ -1,20 +1,31 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
+use async_trait::async_trait;
+use futures::stream::BoxStream;
 use sea_query::{Cond, Expr, Iden, PostgresQueryBuilder, Query};
 use sea_query_binder::SqlxBinder;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use serde_json::{json, Value};
+use sqlx::{FromRow, PgConnection};
 use strum::{EnumIter, IntoEnumIterator};
+use time::OffsetDateTime;
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
 
 use super::{
-    base::{bmc_list, DbBmc},
-    Benchmark, Error, ModelManager, Result,
+    audit::{AuditBmc, AuditContext, Auditable},
+    base::{bmc_list, bmc_list_on, bmc_stream, bmc_upsert, DbBmc, UpsertBmc},
+    outcome::BatchOutcome,
+    validation::{valid_years, FieldError, Validate, Validator},
+    Benchmark, BenchmarkBmc, BenchmarkFilter, EntityId, Error, ModelManager, Result,
 };
 
 // So yeah, this layout is maybe not ideal, but since there's a fixed number of
 // days this at least allows simpler queries asking about specific days that if
 // we stored as json or something
-#[derive(Debug, Default, Clone, PartialEq, PartialOrd, FromRow, Serialize, Deserialize)]
+#[derive(
+    Debug, Default, Clone, PartialEq, PartialOrd, FromRow, Serialize, Deserialize, ToSchema,
+)]
 pub struct Summary {
     pub year: i32,
     pub participant: String,
@@ -84,6 +95,67 @@ impl Summary {
     }
 }
 
+impl Auditable for Summary {
+    fn audit_keys(&self) -> Value {
+        json!({"year": self.year, "participant": self.participant})
+    }
+
+    fn audit_year(&self) -> i32 {
+        self.year
+    }
+
+    fn audit_participant(&self) -> &str {
+        &self.participant
+    }
+}
+
+impl Validate for Summary {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut v = Validator::default();
+
+        v.range("year", self.year, valid_years());
+        v.string("participant", &self.participant, 256);
+        v.string("language", &self.language, 256);
+
+        let times = [
+            ("day_1", self.day_1),
+            ("day_2", self.day_2),
+            ("day_3", self.day_3),
+            ("day_4", self.day_4),
+            ("day_5", self.day_5),
+            ("day_6", self.day_6),
+            ("day_7", self.day_7),
+            ("day_8", self.day_8),
+            ("day_9", self.day_9),
+            ("day_10", self.day_10),
+            ("day_11", self.day_11),
+            ("day_12", self.day_12),
+            ("day_13", self.day_13),
+            ("day_14", self.day_14),
+            ("day_15", self.day_15),
+            ("day_16", self.day_16),
+            ("day_17", self.day_17),
+            ("day_18", self.day_18),
+            ("day_19", self.day_19),
+            ("day_20", self.day_20),
+            ("day_21", self.day_21),
+            ("day_22", self.day_22),
+            ("day_23", self.day_23),
+            ("day_24", self.day_24),
+            ("day_25", self.day_25),
+            ("total", self.total),
+        ];
+
+        for (field, time) in times {
+            if let Some(time) = time {
+                v.duration(field, time);
+            }
+        }
+
+        v.finish()
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 struct SummaryAccumulator {
     year: i32,
@@ -197,6 +269,8 @@ pub enum SummaryIden {
 
 pub struct SummaryBmc;
 
+const GENERATIONS_TABLE: &str = "summary_generations";
+
 impl DbBmc for SummaryBmc {
     const TABLE: &'static str = "summaries";
     type Iden = SummaryIden;
@@ -207,16 +281,154 @@ impl DbBmc for SummaryBmc {
 }
 
 impl SummaryBmc {
-    // we can just take the whole summary since there's no auto key
+    /// Build the summaries for `year` from every stored benchmark, optionally
+    /// only for a single participant. Nothing is written.
+    #[instrument(name = "SummaryBmc::generate", skip(mm))]
+    pub async fn generate(
+        mm: &ModelManager,
+        year: i32,
+        participant: Option<String>,
+    ) -> Result<Vec<Summary>> {
+        let filter = BenchmarkFilter {
+            year: Some(year),
+            participant,
+            ..Default::default()
+        };
+
+        tracing::debug!("finding benchmarks for {year}");
+        let benchmarks = BenchmarkBmc::list(mm, filter).await?;
+        let num_benches = benchmarks.len();
+
+        let summaries = Summary::from_benchmarks(benchmarks)?;
+        tracing::debug!(
+            "generated {} summaries from {} benchmarks",
+            summaries.len(),
+            num_benches
+        );
+
+        Ok(summaries)
+    }
+
+    /// Create new Summaries or update existing ones on a collision, reporting
+    /// what happened to each.
     pub async fn batch_create_or_update<Iter>(
         mm: &ModelManager,
+        ctx: &AuditContext,
         data: Iter,
-    ) -> Result<Vec<(i32, String)>>
+    ) -> Result<Vec<BatchOutcome<Summary>>>
     where
         Iter: IntoIterator<Item = Summary>,
     {
+        bmc_upsert::<Self>(mm, ctx, data.into_iter().collect(), false).await
+    }
+
+    pub async fn list(mm: &ModelManager, filter: SummaryFilter) -> Result<Vec<Summary>> {
+        bmc_list::<Self, _, _>(mm, filter).await
+    }
+
+    /// Stream every summary matching `filter`, for results too large to list.
+    pub fn stream(mm: &ModelManager, filter: SummaryFilter) -> BoxStream<'static, Result<Summary>> {
+        bmc_stream::<Self, _, _>(mm, filter)
+    }
+
+    #[instrument(name = "SummaryBmc::get", skip(mm))]
+    pub async fn get(mm: &ModelManager, year: i32, participant: &str) -> Result<Summary> {
+        let db = mm.db();
+
+        let (sql, values) = Query::select()
+            .columns(SummaryIden::iter())
+            .from(SummaryIden::Table)
+            .and_where(Expr::col(SummaryIden::Year).eq(year))
+            .and_where(Expr::col(SummaryIden::Participant).eq(participant))
+            .build_sqlx(PostgresQueryBuilder);
+
+        let entity = sqlx::query_as_with(&sql, values)
+            .fetch_optional(db)
+            .await?
+            .ok_or_else(|| Error::EntityNotFound {
+                table: SummaryBmc::TABLE,
+                id: EntityId::Summary {
+                    year,
+                    participant: participant.to_string(),
+                },
+            })?;
+
+        Ok(entity)
+    }
+
+    /// Note that every summary of `year` was generated from the benchmarks as
+    /// they were at `at`, even if none of them changed.
+    #[instrument(name = "SummaryBmc::mark_generated", skip(mm))]
+    pub async fn mark_generated(mm: &ModelManager, year: i32, at: OffsetDateTime) -> Result<()> {
+        let db = mm.db();
+
+        // a slower generation that started earlier must not move it back
+        sqlx::query(&format!(
+            "INSERT INTO {GENERATIONS_TABLE} (year, generated_at) VALUES ($1, $2) \
+             ON CONFLICT (year) DO UPDATE SET generated_at = \
+               greatest({GENERATIONS_TABLE}.generated_at, excluded.generated_at)"
+        ))
+        .bind(year)
+        .bind(at)
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The years whose benchmarks changed more than `grace` ago without the
+    /// summaries being generated since, ordered by year.
+    ///
+    /// Changes come from the audit log and generations from
+    /// [Self::mark_generated], so a generation counts even when it left every
+    /// summary as it was.
+    #[instrument(name = "SummaryBmc::stale_years", skip(mm))]
+    pub async fn stale_years(mm: &ModelManager, grace: Duration) -> Result<Vec<i32>> {
         let db = mm.db();
 
+        let cutoff = OffsetDateTime::now_utc() - grace;
+
+        let years = sqlx::query_scalar(&format!(
+            "SELECT b.year FROM \
+               (SELECT year, max(created_at) AS at FROM {audit} \
+                WHERE entity = $1 AND year IS NOT NULL GROUP BY year) b \
+             LEFT JOIN {GENERATIONS_TABLE} g ON g.year = b.year \
+             WHERE b.at < $2 AND (g.generated_at IS NULL OR g.generated_at < b.at) \
+             ORDER BY b.year",
+            audit = AuditBmc::TABLE,
+        ))
+        .bind(BenchmarkBmc::TABLE)
+        .bind(cutoff)
+        .fetch_all(db)
+        .await?;
+
+        Ok(years)
+    }
+}
+
+#[async_trait]
+impl UpsertBmc for SummaryBmc {
+    // we can just take the whole summary since there's no auto key
+    type Create = Summary;
+    type Entity = Summary;
+
+    async fn list_by_keys(conn: &mut PgConnection, data: &[Summary]) -> Result<Vec<Summary>> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let cond = data.iter().fold(Cond::any(), |cond, s| {
+            cond.add(
+                Cond::all()
+                    .add(Expr::col(SummaryIden::Year).eq(s.year))
+                    .add(Expr::col(SummaryIden::Participant).eq(s.participant.as_str())),
+            )
+        });
+
+        bmc_list_on::<Self, _, _>(conn, cond).await
+    }
+
+    async fn upsert(conn: &mut PgConnection, data: &[Summary]) -> Result<Vec<(EntityId, bool)>> {
         let mut query = Query::insert();
 
         query
@@ -224,43 +436,47 @@ impl SummaryBmc {
             .columns(SummaryIden::iter().filter(|v| !matches!(v, SummaryIden::Table)));
 
         for summary_data in data {
-            query.values_panic([
-                summary_data.year.into(),
-                summary_data.participant.into(),
-                summary_data.language.into(),
-                summary_data.day_1.into(),
-                summary_data.day_2.into(),
-                summary_data.day_3.into(),
-                summary_data.day_4.into(),
-                summary_data.day_5.into(),
-                summary_data.day_6.into(),
-                summary_data.day_7.into(),
-                summary_data.day_8.into(),
-                summary_data.day_9.into(),
-                summary_data.day_10.into(),
-                summary_data.day_11.into(),
-                summary_data.day_12.into(),
-                summary_data.day_13.into(),
-                summary_data.day_14.into(),
-                summary_data.day_15.into(),
-                summary_data.day_16.into(),
-                summary_data.day_17.into(),
-                summary_data.day_18.into(),
-                summary_data.day_19.into(),
-                summary_data.day_20.into(),
-                summary_data.day_21.into(),
-                summary_data.day_22.into(),
-                summary_data.day_23.into(),
-                summary_data.day_24.into(),
-                summary_data.day_25.into(),
-                summary_data.total.into(),
-            ]);
+            query
+                .values([
+                    summary_data.year.into(),
+                    summary_data.participant.clone().into(),
+                    summary_data.language.clone().into(),
+                    summary_data.day_1.into(),
+                    summary_data.day_2.into(),
+                    summary_data.day_3.into(),
+                    summary_data.day_4.into(),
+                    summary_data.day_5.into(),
+                    summary_data.day_6.into(),
+                    summary_data.day_7.into(),
+                    summary_data.day_8.into(),
+                    summary_data.day_9.into(),
+                    summary_data.day_10.into(),
+                    summary_data.day_11.into(),
+                    summary_data.day_12.into(),
+                    summary_data.day_13.into(),
+                    summary_data.day_14.into(),
+                    summary_data.day_15.into(),
+                    summary_data.day_16.into(),
+                    summary_data.day_17.into(),
+                    summary_data.day_18.into(),
+                    summary_data.day_19.into(),
+                    summary_data.day_20.into(),
+                    summary_data.day_21.into(),
+                    summary_data.day_22.into(),
+                    summary_data.day_23.into(),
+                    summary_data.day_24.into(),
+                    summary_data.day_25.into(),
+                    summary_data.total.into(),
+                ])
+                .map_err(anyhow::Error::from)?;
         }
 
         let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
 
         // probably not the best hack, but sea-query doesn't allow for setting
         // the ON CONSTRAINT expression.
+        //
+        // xmax is only zero for rows that were freshly inserted
         let sql = sql
             + r#" ON CONFLICT (year, participant) DO UPDATE SET
 language = excluded.language,
@@ -290,43 +506,35 @@ day_23 = excluded.day_23,
 day_24 = excluded.day_24,
 day_25 = excluded.day_25,
 total = excluded.total
-RETURNING year, participant"#;
+RETURNING year, participant, (xmax = 0) AS inserted"#;
 
-        let ids = sqlx::query_as_with::<_, (i32, String), _>(&sql, values)
-            .fetch_all(db)
+        let written = sqlx::query_as_with::<_, (i32, String, bool), _>(&sql, values)
+            .fetch_all(conn)
             .await?;
 
-        Ok(ids)
+        Ok(written
+            .into_iter()
+            .map(|(year, participant, inserted)| {
+                (EntityId::Summary { year, participant }, inserted)
+            })
+            .collect())
     }
 
-    pub async fn list(mm: &ModelManager, filter: SummaryFilter) -> Result<Vec<Summary>> {
-        bmc_list::<Self, _, _>(mm, filter).await
+    fn entity_id(stored: &Summary) -> EntityId {
+        EntityId::Summary {
+            year: stored.year,
+            participant: stored.participant.clone(),
+        }
     }
 
-    pub async fn get(mm: &ModelManager, year: i32, participant: &str) -> Result<Summary> {
-        let db = mm.db();
-
-        let (sql, values) = Query::select()
-            .columns(SummaryIden::iter())
-            .from(SummaryIden::Table)
-            .and_where(Expr::col(SummaryIden::Year).eq(year))
-            .and_where(Expr::col(SummaryIden::Participant).eq(participant))
-            .build_sqlx(PostgresQueryBuilder);
-
-        let entity = sqlx::query_as_with(&sql, values)
-            .fetch_optional(db)
-            .await?
-            .ok_or(Error::EntityNotFound {
-                table: SummaryBmc::TABLE,
-                id: year,
-            })?;
-
-        Ok(entity)
+    fn is_unchanged(data: &Summary, stored: &Summary) -> bool {
+        data == stored
     }
 }
 
-#[derive(Debug, Default, Clone, Deserialize)]
+#[derive(Debug, Default, Clone, Deserialize, IntoParams)]
 #[cfg_attr(test, derive(Serialize))]
+#[into_params(parameter_in = Query)]
 pub struct SummaryFilter {
     pub year: Option<i32>,
     pub participant: Option<String>,
@@ -357,6 +565,14 @@ impl From<SummaryFilter> for Cond {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{outcome::OutcomeStatus, AuditContext, BenchmarkCreate, EntityId};
+
+    fn summary_id(year: i32, participant: &str) -> Option<EntityId> {
+        Some(EntityId::Summary {
+            year,
+            participant: participant.into(),
+        })
+    }
 
     #[sqlx::test]
     async fn test_create_batch_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
@@ -427,12 +643,16 @@ mod tests {
             },
         ];
 
-        let ids = SummaryBmc::batch_create_or_update(&mm, data.clone()).await?;
+        let outcomes = SummaryBmc::batch_create_or_update(
+            &mm,
+            &AuditContext::new("test", "test"),
+            data.clone(),
+        )
+        .await?;
+        let ids: Vec<_> = outcomes.iter().map(|o| o.id.clone()).collect();
 
-        assert_eq!(
-            ids,
-            vec![(2023, "foo".to_string()), (2023, "bar".to_string())]
-        );
+        assert_eq!(ids, vec![summary_id(2023, "foo"), summary_id(2023, "bar")]);
+        assert!(outcomes.iter().all(|o| o.status == OutcomeStatus::Created));
 
         let summaries = SummaryBmc::list(&mm, SummaryFilter::default()).await?;
 
@@ -505,12 +725,18 @@ mod tests {
             },
         ];
 
-        let ids = SummaryBmc::batch_create_or_update(&mm, data2.clone()).await?;
+        let outcomes = SummaryBmc::batch_create_or_update(
+            &mm,
+            &AuditContext::new("test", "test"),
+            data2.clone(),
+        )
+        .await?;
+        let ids: Vec<_> = outcomes.iter().map(|o| o.id.clone()).collect();
 
-        assert_eq!(
-            ids,
-            vec![(2023, "baz".to_string()), (2023, "bar".to_string())]
-        );
+        assert_eq!(ids, vec![summary_id(2023, "baz"), summary_id(2023, "bar")]);
+        assert_eq!(outcomes[0].status, OutcomeStatus::Created);
+        assert_eq!(outcomes[1].status, OutcomeStatus::Updated);
+        assert_eq!(outcomes[1].previous.as_ref(), Some(&data[1]));
 
         let mut summaries = SummaryBmc::list(&mm, SummaryFilter::default()).await?;
         // yeah, only partial ord
@@ -563,6 +789,96 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test(fixtures("../../fixtures/benchmarks.sql"))]
+    async fn test_generate_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+
+        let summaries = SummaryBmc::generate(&mm, 2023, None).await?;
+        assert_eq!(summaries.len(), 2);
+
+        let summaries = SummaryBmc::generate(&mm, 2023, Some("foo".into())).await?;
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].participant, "foo");
+
+        assert!(SummaryBmc::generate(&mm, 2019, None).await?.is_empty());
+
+        // generating does not write anything
+        assert!(SummaryBmc::list(&mm, SummaryFilter::default())
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/benchmarks.sql"))]
+    async fn test_stale_years_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let ctx = AuditContext::new("test", "POST /");
+
+        // nothing in the audit log yet, so nothing to go on
+        assert!(SummaryBmc::stale_years(&mm, Duration::ZERO)
+            .await?
+            .is_empty());
+
+        let data = [BenchmarkCreate {
+            year: 2023,
+            day: 3,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            ..Default::default()
+        }];
+        BenchmarkBmc::batch_create_or_update(&mm, &ctx, data).await?;
+
+        assert_eq!(SummaryBmc::stale_years(&mm, Duration::ZERO).await?, [2023]);
+        // still within the grace period
+        assert!(SummaryBmc::stale_years(&mm, Duration::from_secs(60))
+            .await?
+            .is_empty());
+
+        let started = OffsetDateTime::now_utc();
+        let summaries = SummaryBmc::generate(&mm, 2023, None).await?;
+        SummaryBmc::batch_create_or_update(&mm, &ctx, summaries).await?;
+        SummaryBmc::mark_generated(&mm, 2023, started).await?;
+
+        assert!(SummaryBmc::stale_years(&mm, Duration::ZERO)
+            .await?
+            .is_empty());
+
+        // summaries only use the mean, so this leaves them as they were
+        let data = [BenchmarkCreate {
+            year: 2023,
+            day: 3,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            stddev: 0.5,
+            ..Default::default()
+        }];
+        BenchmarkBmc::batch_create_or_update(&mm, &ctx, data).await?;
+        assert_eq!(SummaryBmc::stale_years(&mm, Duration::ZERO).await?, [2023]);
+
+        let started = OffsetDateTime::now_utc();
+        let summaries = SummaryBmc::generate(&mm, 2023, None).await?;
+        let outcomes = SummaryBmc::batch_create_or_update(&mm, &ctx, summaries).await?;
+        assert!(outcomes
+            .iter()
+            .all(|o| o.status == OutcomeStatus::Unchanged));
+        SummaryBmc::mark_generated(&mm, 2023, started).await?;
+
+        assert!(SummaryBmc::stale_years(&mm, Duration::ZERO)
+            .await?
+            .is_empty());
+
+        // an earlier generation finishing late doesn't undo a later one
+        SummaryBmc::mark_generated(&mm, 2023, started - Duration::from_secs(60)).await?;
+        assert!(SummaryBmc::stale_years(&mm, Duration::ZERO)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
     #[test]
     fn from_benchmarks() -> anyhow::Result<()> {
         let benchmarks = vec![
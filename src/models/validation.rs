@@ -0,0 +1,166 @@
+use std::{fmt, ops::RangeInclusive};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+
+/// The first year of advent of code.
+const FIRST_YEAR: i32 = 2015;
+
+/// The range of years we will accept data for.
+pub fn valid_years() -> RangeInclusive<i32> {
+    FIRST_YEAR..=OffsetDateTime::now_utc().year()
+}
+
+/// A single problem with a single field.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// All of the problems with a record at `index` within a batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecordErrors {
+    pub index: usize,
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct ValidationErrors(pub Vec<RecordErrors>);
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let num_fields: usize = self.0.iter().map(|r| r.fields.len()).sum();
+        write!(
+            f,
+            "{} invalid field(s) in {} record(s)",
+            num_fields,
+            self.0.len()
+        )
+    }
+}
+
+/// Something that can be checked before it's handed to the database.
+pub trait Validate {
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+/// Accumulates field errors so implementations of [Validate] read as a list of
+/// rules.
+#[derive(Debug, Default)]
+pub(in crate::models) struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, valid: bool, field: &'static str, message: impl Into<String>) {
+        if !valid {
+            self.errors.push(FieldError {
+                field,
+                message: message.into(),
+            });
+        }
+    }
+
+    pub fn range(&mut self, field: &'static str, value: i32, range: RangeInclusive<i32>) {
+        self.check(
+            range.contains(&value),
+            field,
+            format!(
+                "must be between {} and {} (inclusive)",
+                range.start(),
+                range.end()
+            ),
+        );
+    }
+
+    /// Non-empty and at most `max_len` characters, matching the column sizes.
+    pub fn string(&mut self, field: &'static str, value: &str, max_len: usize) {
+        if value.trim().is_empty() {
+            self.check(false, field, "must not be empty");
+        } else {
+            self.check(
+                value.chars().count() <= max_len,
+                field,
+                format!("must be at most {max_len} characters"),
+            );
+        }
+    }
+
+    pub fn duration(&mut self, field: &'static str, value: f64) {
+        if !value.is_finite() {
+            self.check(false, field, "must be a finite number");
+        } else {
+            self.check(value >= 0.0, field, "must not be negative");
+        }
+    }
+
+    pub fn finish(self) -> Vec<FieldError> {
+        self.errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Thing {
+        name: String,
+        value: f64,
+    }
+
+    impl Validate for Thing {
+        fn validate(&self) -> Vec<FieldError> {
+            let mut v = Validator::default();
+            v.string("name", &self.name, 4);
+            v.duration("value", self.value);
+            v.finish()
+        }
+    }
+
+    #[test]
+    fn validator_reports_every_field() {
+        let ok = Thing {
+            name: "ok".into(),
+            value: 1.0,
+        };
+        let empty = Thing {
+            name: "".into(),
+            value: f64::NAN,
+        };
+        let long = Thing {
+            name: "too long".into(),
+            value: -1.0,
+        };
+
+        assert!(ok.validate().is_empty());
+        assert_eq!(
+            empty.validate(),
+            vec![
+                FieldError {
+                    field: "name",
+                    message: "must not be empty".into()
+                },
+                FieldError {
+                    field: "value",
+                    message: "must be a finite number".into()
+                },
+            ]
+        );
+        assert_eq!(
+            long.validate(),
+            vec![
+                FieldError {
+                    field: "name",
+                    message: "must be at most 4 characters".into()
+                },
+                FieldError {
+                    field: "value",
+                    message: "must not be negative".into()
+                },
+            ]
+        );
+    }
+}
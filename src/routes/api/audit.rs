@@ -0,0 +1,133 @@
+use axum::{
+    extract::{Query, State},
+    middleware, Json,
+};
+use tracing::instrument;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    error::ErrorEnvelope,
+    middleware::{mw_rate_limit, mw_require_auth},
+    models::{AuditBmc, AuditEntry, AuditFilter, ModelManager},
+    server::AppState,
+    Result,
+};
+
+// the entire audit log is admin-only
+pub fn routes(state: AppState) -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(list_audit_entries))
+        .layer(middleware::from_fn_with_state(
+            state.config(),
+            mw_require_auth,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.expensive_limiter(),
+            mw_rate_limit,
+        ))
+        .with_state(state)
+}
+
+/// List the recorded writes matching the filter, most recent first.
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "audit",
+    params(AuditFilter),
+    responses(
+        (status = 200, description = "The matching audit entries", body = [AuditEntry]),
+        (status = 401, description = "Missing or invalid token", body = ErrorEnvelope),
+        (status = 429, description = "Too many requests", body = ErrorEnvelope),
+    ),
+    security(("api_token" = [])),
+)]
+#[instrument(skip_all)]
+async fn list_audit_entries(
+    State(mm): State<ModelManager>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Json<Vec<AuditEntry>>> {
+    Ok(Json(AuditBmc::list(&mm, filter).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{self, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use crate::models::Participant;
+
+    use super::*;
+    use crate::config::Config;
+
+    // these tests only care about the handlers, not the spec
+    fn router(state: AppState) -> axum::Router {
+        super::routes(state).into()
+    }
+
+    #[sqlx::test(fixtures("../../../fixtures/participants.sql"))]
+    async fn test_list_records_writes(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+
+        let create_args = Participant {
+            year: 2023,
+            name: "foo".into(),
+            language: "rust".into(),
+            repo: "https://foobar/foo2".into(),
+        };
+
+        let response = crate::routes::api::routes(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/api/v1/participants")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header(http::header::AUTHORIZATION, "Bearer sandcastle")
+                    .body(Body::from(serde_json::to_vec(&create_args)?))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = router(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/?participant=foo")
+                    .header(http::header::AUTHORIZATION, "Bearer sandcastle")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body: Vec<AuditEntry> = serde_json::from_slice(&raw_body)?;
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0].actor, "api-token");
+        assert_eq!(body[0].route, "POST /api/v1/participants");
+        assert_eq!(body[0].action, "update");
+        assert_eq!(body[0].entity, "participants");
+        assert_eq!(
+            body[0].before,
+            Some(serde_json::json!({"language": "ruby"}))
+        );
+        assert_eq!(body[0].after, Some(serde_json::json!({"language": "rust"})));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_list_requires_auth(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+
+        let response = router(state)
+            .oneshot(Request::builder().uri("/").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+}
@@ -1,56 +1,152 @@
 use axum::{
     extract::{Path, Query, State},
     middleware,
-    routing::{get, post},
-    Json, Router,
+    response::Response,
+    Extension, Json,
 };
 use serde::Deserialize;
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{
+    router::{OpenApiRouter, UtoipaMethodRouterExt},
+    routes,
+};
 
+use super::format::{FormatParams, ListFormat};
 use crate::{
-    middleware::mw_require_auth,
-    models::{Benchmark, BenchmarkBmc, BenchmarkCreate, BenchmarkFilter, ModelManager},
+    error::ErrorEnvelope,
+    middleware::{mw_idempotency, mw_rate_limit, mw_require_auth},
+    models::{
+        AuditContext, BatchOutcome, Benchmark, BenchmarkBmc, BenchmarkCreate, BenchmarkFilter,
+        ModelManager,
+    },
     server::AppState,
     Result,
 };
 
-pub fn routes(state: AppState) -> Router {
-    Router::new()
-        .route("/", post(create_benchmarks))
-        .layer(middleware::from_fn(mw_require_auth))
-        .route("/", get(list_benchmarks))
-        .route("/:benchmark", get(get_benchmark))
+pub fn routes(state: AppState) -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(create_benchmarks))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            mw_idempotency,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.config(),
+            mw_require_auth,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.write_limiter(),
+            mw_rate_limit,
+        ))
+        .routes(
+            routes!(list_benchmarks).layer(middleware::from_fn_with_state(
+                state.expensive_limiter(),
+                mw_rate_limit,
+            )),
+        )
+        .routes(routes!(get_benchmark))
         .with_state(state)
 }
 
 // TODO: maybe this is the wrong place for this, but we want the _endpoint_ to
 // accept single _and_ multiple records. This is probably something the
 // controller doesn't need to be aware of - MCL - 2023-11-21
-#[derive(Debug, Clone, Deserialize)]
+/// A single benchmark, or an array of them.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 #[serde(untagged)]
+#[schema(as = BenchmarkCreateRequest)]
 enum CreateRequest {
     One(BenchmarkCreate),
     Many(Vec<BenchmarkCreate>),
 }
 
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CreateParams {
+    /// Accept the valid records of a batch even if some are invalid.
+    #[serde(default)]
+    partial: bool,
+}
+
+/// Record benchmark results, replacing any previous results for the same
+/// participant, year, day and input, along with the language they were in.
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "benchmarks",
+    params(
+        CreateParams,
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key"),
+    ),
+    request_body = CreateRequest,
+    responses(
+        (status = 200, description = "What happened to each record", body = [BatchOutcome<Benchmark>]),
+        (status = 400, description = "The batch is empty, or the idempotency key is malformed", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid token", body = ErrorEnvelope),
+        (status = 409, description = "A request with this idempotency key is in progress", body = ErrorEnvelope),
+        (status = 422, description = "Some records are invalid, or the idempotency key was used for a different request", body = ErrorEnvelope),
+        (status = 429, description = "Too many requests", body = ErrorEnvelope),
+    ),
+    security(("api_token" = [])),
+)]
+#[instrument(skip_all)]
 async fn create_benchmarks(
     State(mm): State<ModelManager>,
+    Extension(ctx): Extension<AuditContext>,
+    Query(params): Query<CreateParams>,
     Json(payload): Json<CreateRequest>,
-) -> Result<Json<Vec<i32>>> {
+) -> Result<Json<Vec<BatchOutcome<Benchmark>>>> {
     let data = match payload {
         CreateRequest::One(v) => vec![v],
         CreateRequest::Many(v) => v,
     };
 
-    Ok(Json(BenchmarkBmc::batch_create_or_update(&mm, data).await?))
+    let outcomes = if params.partial {
+        BenchmarkBmc::batch_create_or_update_partial(&mm, &ctx, data).await?
+    } else {
+        BenchmarkBmc::batch_create_or_update(&mm, &ctx, data).await?
+    };
+
+    Ok(Json(outcomes))
 }
 
+/// List the benchmarks matching the filter.
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "benchmarks",
+    params(BenchmarkFilter, FormatParams),
+    responses(
+        (status = 200, description = "The matching benchmarks", content(
+            ([Benchmark] = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson"),
+        )),
+        (status = 400, description = "Unsupported format", body = ErrorEnvelope),
+        (status = 429, description = "Too many requests", body = ErrorEnvelope),
+    ),
+)]
+#[instrument(skip_all)]
 async fn list_benchmarks(
     State(mm): State<ModelManager>,
+    format: ListFormat,
     Query(filter): Query<BenchmarkFilter>,
-) -> Result<Json<Vec<Benchmark>>> {
-    Ok(Json(BenchmarkBmc::list(&mm, filter).await?))
+) -> Result<Response> {
+    format.respond(BenchmarkBmc::stream(&mm, filter)).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/{benchmark}",
+    tag = "benchmarks",
+    params(("benchmark" = i32, Path, description = "The benchmark id")),
+    responses(
+        (status = 200, description = "The benchmark", body = Benchmark),
+        (status = 404, description = "No such benchmark", body = ErrorEnvelope),
+    ),
+)]
+#[instrument(skip(mm))]
 async fn get_benchmark(
     State(mm): State<ModelManager>,
     Path(id): Path<i32>,
@@ -61,13 +157,20 @@ async fn get_benchmark(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Config;
     use axum::{
         body::{to_bytes, Body},
         http::{self, Request, StatusCode},
     };
+    use serde_json::{json, Value};
     use std::f64::EPSILON;
     use tower::ServiceExt;
 
+    // these tests only care about the handlers, not the spec
+    fn router(state: AppState) -> axum::Router {
+        super::routes(state).into()
+    }
+
     // we need to do this to check the floating point values
     macro_rules! assert_benchmarks_equal {
         ($a:expr, $b:expr) => {
@@ -88,8 +191,8 @@ mod tests {
 
     #[sqlx::test]
     async fn test_create_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = super::routes(state.clone());
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state.clone());
 
         let create_args = BenchmarkCreate {
             year: 2023,
@@ -121,12 +224,11 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
 
         let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
-        let body: Vec<i32> = serde_json::from_slice(&raw_body)?;
-        assert_eq!(body.len(), 1);
-        assert_eq!(body, vec![1000]);
+        let body: Value = serde_json::from_slice(&raw_body)?;
+        assert_eq!(body, json!([{"index": 0, "status": "created", "id": 1000}]));
 
         // we need to get that created object back
-        let routes = super::routes(state);
+        let routes = router(state);
         let response = routes
             .oneshot(Request::builder().uri("/1000").body(Body::empty()).unwrap())
             .await?;
@@ -142,8 +244,8 @@ mod tests {
 
     #[sqlx::test]
     async fn test_create_batch_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = super::routes(state.clone());
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state.clone());
 
         let create_args = vec![
             BenchmarkCreate {
@@ -191,12 +293,17 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
 
         let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
-        let body: Vec<i32> = serde_json::from_slice(&raw_body)?;
-        assert_eq!(body.len(), 2);
-        assert_eq!(body, vec![1000, 1001]);
+        let body: Value = serde_json::from_slice(&raw_body)?;
+        assert_eq!(
+            body,
+            json!([
+                {"index": 0, "status": "created", "id": 1000},
+                {"index": 1, "status": "created", "id": 1001},
+            ])
+        );
 
         // we want to get those objects back, so just list
-        let routes = super::routes(state);
+        let routes = router(state);
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
             .await?;
@@ -213,7 +320,7 @@ mod tests {
 
     #[sqlx::test]
     async fn test_create_requires_auth(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
         let create_args = vec![
             BenchmarkCreate {
@@ -246,7 +353,7 @@ mod tests {
             },
         ];
 
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(
@@ -261,7 +368,7 @@ mod tests {
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 
         // and with wrong token
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(
@@ -279,10 +386,171 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test]
+    async fn test_create_invalid(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state.clone());
+
+        let valid = BenchmarkCreate {
+            year: 2023,
+            day: 12,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            mean: 0.57,
+            stddev: 0.07,
+            median: 0.52,
+            user: 0.44,
+            system: 0.13,
+            min: 0.20,
+            max: 0.71,
+        };
+
+        let create_args = vec![
+            valid.clone(),
+            BenchmarkCreate {
+                year: 1900,
+                day: 0,
+                participant: "".into(),
+                median: 0.9,
+                ..valid.clone()
+            },
+        ];
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header(http::header::AUTHORIZATION, "Bearer sandcastle")
+                    .body(Body::from(serde_json::to_vec(&create_args)?))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body: Value = serde_json::from_slice(&raw_body)?;
+        assert_eq!(body["error"]["code"], "validation_failed");
+
+        let records = body["error"]["details"]["records"].as_array().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["index"], 1);
+
+        let fields: Vec<_> = records[0]["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(fields, vec!["year", "day", "participant", "median"]);
+
+        // nothing was written
+        let routes = router(state);
+        let response = routes
+            .oneshot(Request::builder().uri("/").body(Body::empty())?)
+            .await?;
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body: Vec<Benchmark> = serde_json::from_slice(&raw_body)?;
+        assert!(body.is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_partial(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state.clone());
+
+        let valid = BenchmarkCreate {
+            year: 2023,
+            day: 12,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            mean: 0.57,
+            stddev: 0.07,
+            median: 0.52,
+            user: 0.44,
+            system: 0.13,
+            min: 0.20,
+            max: 0.71,
+        };
+
+        let create_args = vec![
+            BenchmarkCreate {
+                day: 0,
+                ..valid.clone()
+            },
+            valid.clone(),
+        ];
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/?partial=true")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header(http::header::AUTHORIZATION, "Bearer sandcastle")
+                    .body(Body::from(serde_json::to_vec(&create_args)?))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body: Value = serde_json::from_slice(&raw_body)?;
+        assert_eq!(
+            body,
+            json!([
+                {
+                    "index": 0,
+                    "status": "rejected",
+                    "reason": "invalid",
+                    "errors": [{"field": "day", "message": "must be between 1 and 25 (inclusive)"}],
+                },
+                {"index": 1, "status": "created", "id": 1000},
+            ])
+        );
+
+        let routes = router(state);
+        let response = routes
+            .oneshot(Request::builder().uri("/1000").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_body_too_large(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        // the body limit is applied to the entire api
+        let routes = crate::routes::api::routes(state);
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/api/v1/benchmarks")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header(http::header::AUTHORIZATION, "Bearer sandcastle")
+                    .body(Body::from(vec![b' '; 3 * 1024 * 1024]))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        Ok(())
+    }
+
     #[sqlx::test(fixtures("../../../fixtures/benchmarks.sql"))]
     async fn test_list_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
@@ -300,8 +568,8 @@ mod tests {
 
     #[sqlx::test]
     async fn test_list_empty_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
@@ -319,8 +587,8 @@ mod tests {
 
     #[sqlx::test(fixtures("../../../fixtures/benchmarks.sql"))]
     async fn test_list_filterd_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(
@@ -341,10 +609,118 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test(fixtures("../../../fixtures/benchmarks.sql"))]
+    async fn test_list_csv_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .uri("/?participant=bar")
+                    .header(http::header::ACCEPT, "text/csv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            "text/csv; charset=utf-8"
+        );
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body = String::from_utf8(raw_body.to_vec())?;
+        let lines: Vec<_> = body.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "id,year,day,input,participant,language,mean,stddev,median,user,system,min,max"
+        );
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1..].iter().all(|l| l.contains(",bar,")));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("../../../fixtures/benchmarks.sql"))]
+    async fn test_list_ndjson_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .uri("/?year=2023")
+                    .header(http::header::ACCEPT, "application/x-ndjson")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body: Vec<Benchmark> = raw_body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(body.len(), 4);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_list_ndjson_empty_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .uri("/?format=ndjson")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        assert!(raw_body.is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_list_unsupported_format(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .uri("/?format=xml")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
     #[sqlx::test(fixtures("../../../fixtures/benchmarks.sql"))]
     async fn test_get_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(Request::builder().uri("/1001").body(Body::empty()).unwrap())
@@ -362,8 +738,8 @@ mod tests {
 
     #[sqlx::test]
     async fn test_get_not_found(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(Request::builder().uri("/50").body(Body::empty()).unwrap())
@@ -373,4 +749,28 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn test_errors_carry_request_id(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let app = crate::routes::router(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/v1/benchmarks/50")
+                    .header("x-request-id", "abc-123")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["x-request-id"], "abc-123");
+
+        let body: Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await?)?;
+        assert_eq!(body["error"]["request_id"], "abc-123");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,256 @@
+use async_trait::async_trait;
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use utoipa::IntoParams;
+
+use crate::{models, Error, Result};
+
+const TEXT_CSV: &str = "text/csv";
+const APPLICATION_NDJSON: &str = "application/x-ndjson";
+
+/// How a list endpoint should represent its results, chosen by the `format`
+/// query parameter or, failing that, the `Accept` header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListFormat {
+    #[default]
+    Json,
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct FormatParams {
+    /// One of `json`, `csv` or `ndjson`, taking precedence over `Accept`.
+    format: Option<String>,
+}
+
+impl ListFormat {
+    fn from_param(value: &str) -> Result<Self> {
+        match value {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            "ndjson" => Ok(Self::Ndjson),
+            other => Err(Error::UnsupportedFormat(other.to_string())),
+        }
+    }
+
+    // the type we know with the highest quality wins, the first one on a tie
+    fn from_accept(value: &str) -> Option<Self> {
+        value
+            .split(',')
+            .filter_map(|v| {
+                let mut params = v.split(';');
+                let format = match params.next().unwrap_or_default().trim() {
+                    "application/json" => Self::Json,
+                    TEXT_CSV => Self::Csv,
+                    APPLICATION_NDJSON => Self::Ndjson,
+                    _ => return None,
+                };
+
+                let quality = params
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                (quality > 0.0).then_some((format, quality))
+            })
+            .fold(
+                None,
+                |best: Option<(Self, f32)>, (format, quality)| match best {
+                    Some((_, q)) if q >= quality => best,
+                    _ => Some((format, quality)),
+                },
+            )
+            .map(|(format, _)| format)
+    }
+
+    /// Build the response from the stream of matching records.
+    ///
+    /// JSON is a single array, so it is collected first. The other formats are
+    /// line oriented and stream each record as the database produces it.
+    pub async fn respond<T>(
+        self,
+        records: BoxStream<'static, models::Result<T>>,
+    ) -> Result<Response>
+    where
+        T: Serialize + Default + Send + 'static,
+    {
+        if self == Self::Json {
+            let records: Vec<T> = records.try_collect().await?;
+            return Ok(Json(records).into_response());
+        }
+
+        // once the first bytes are sent we can no longer respond with an error,
+        // so wait for the first record to catch things like a bad connection
+        let mut records = records;
+        let first = records.next().await.transpose()?;
+        let records = futures::stream::iter(first.map(Ok))
+            .chain(records)
+            .inspect_err(|e| tracing::error!("aborting streamed response: {e:?}"));
+
+        let (content_type, body) = match self {
+            Self::Ndjson => (
+                APPLICATION_NDJSON,
+                Body::from_stream(records.map(|record| ndjson_line(record?))),
+            ),
+            _ => {
+                // taken from a placeholder, so there's a header without records
+                let header = csv_header::<T>()?;
+
+                (
+                    "text/csv; charset=utf-8",
+                    Body::from_stream(
+                        futures::stream::once(async { Ok(header) })
+                            .chain(records.map(|record| csv_line(record?, false))),
+                    ),
+                )
+            }
+        };
+
+        Ok((
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            body,
+        )
+            .into_response())
+    }
+}
+
+fn csv_line<T: Serialize>(record: T, with_headers: bool) -> Result<Bytes> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(with_headers)
+        .from_writer(Vec::new());
+
+    writer.serialize(record).map_err(anyhow::Error::from)?;
+    let line = writer.into_inner().map_err(|e| anyhow::anyhow!("{e}"))?;
+
+    Ok(Bytes::from(line))
+}
+
+fn csv_header<T: Serialize + Default>() -> Result<Bytes> {
+    let line = csv_line(T::default(), true)?;
+    let end = line
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(line.len(), |i| i + 1);
+
+    Ok(line.slice(..end))
+}
+
+fn ndjson_line<T: Serialize>(record: T) -> Result<Bytes> {
+    let mut line = serde_json::to_vec(&record).map_err(anyhow::Error::from)?;
+    line.push(b'\n');
+
+    Ok(Bytes::from(line))
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ListFormat {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self> {
+        // the filters are extracted from the same query, so anything we fail to
+        // parse here will be rejected there
+        if let Ok(Query(FormatParams {
+            format: Some(format),
+        })) = Query::<FormatParams>::from_request_parts(parts, state).await
+        {
+            return Self::from_param(&format);
+        }
+
+        Ok(parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Self::from_accept)
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_parsing() {
+        assert_eq!(ListFormat::from_accept("text/csv"), Some(ListFormat::Csv));
+        assert_eq!(
+            ListFormat::from_accept("text/csv; charset=utf-8, application/json"),
+            Some(ListFormat::Csv)
+        );
+        assert_eq!(
+            ListFormat::from_accept("text/html, application/json;q=0.9"),
+            Some(ListFormat::Json)
+        );
+        assert_eq!(
+            ListFormat::from_accept("application/x-ndjson"),
+            Some(ListFormat::Ndjson)
+        );
+        assert_eq!(ListFormat::from_accept("*/*"), None);
+
+        // quality values are respected, and zero rules a type out
+        assert_eq!(
+            ListFormat::from_accept("text/csv;q=0.5, application/x-ndjson"),
+            Some(ListFormat::Ndjson)
+        );
+        assert_eq!(
+            ListFormat::from_accept("text/csv;q=0, application/json"),
+            Some(ListFormat::Json)
+        );
+        assert_eq!(ListFormat::from_accept("text/csv; q=0"), None);
+    }
+
+    #[derive(Debug, Default, Serialize)]
+    struct Row {
+        name: String,
+        value: Option<f64>,
+    }
+
+    async fn body(format: ListFormat, rows: Vec<Row>) -> anyhow::Result<String> {
+        let records = futures::stream::iter(rows.into_iter().map(Ok)).boxed();
+        let response = format.respond(records).await?;
+        let raw = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+
+        Ok(String::from_utf8(raw.to_vec())?)
+    }
+
+    #[tokio::test]
+    async fn csv_always_has_header() -> anyhow::Result<()> {
+        assert_eq!(body(ListFormat::Csv, Vec::new()).await?, "name,value\n");
+
+        let rows = vec![
+            Row {
+                name: "a".into(),
+                value: Some(1.5),
+            },
+            Row {
+                name: "b".into(),
+                value: None,
+            },
+        ];
+        assert_eq!(
+            body(ListFormat::Csv, rows).await?,
+            "name,value\na,1.5\nb,\n"
+        );
+
+        assert_eq!(body(ListFormat::Ndjson, Vec::new()).await?, "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn param_parsing() {
+        assert_eq!(ListFormat::from_param("csv").unwrap(), ListFormat::Csv);
+        assert!(matches!(
+            ListFormat::from_param("xml"),
+            Err(Error::UnsupportedFormat(f)) if f == "xml"
+        ));
+    }
+}
@@ -1,18 +1,186 @@
-use axum::Router;
+use axum::{
+    body::Bytes,
+    extract::DefaultBodyLimit,
+    http::{header, HeaderValue},
+    routing::get,
+    Router,
+};
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_redoc::{Redoc, Servable};
 
 use crate::server::AppState;
 
+mod audit;
 mod benchmarks;
+mod format;
 mod participants;
 mod summaries;
 
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        description = "Benchmark results for Advent of Code solutions",
+        license(name = "MIT"),
+    ),
+    tags(
+        (name = "audit", description = "Every write made through the API"),
+        (name = "benchmarks", description = "Timings of each participant's solutions"),
+        (name = "participants", description = "Who is taking part each year"),
+        (name = "summaries", description = "Per-day results for each participant, derived from the benchmarks"),
+    ),
+    modifiers(&ApiToken),
+)]
+struct ApiDoc;
+
+/// Writes and the audit log need the API token as a bearer token.
+struct ApiToken;
+
+impl Modify for ApiToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "api_token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+    }
+}
+
 pub fn routes(state: AppState) -> Router {
-    Router::new().nest("/api/v1", v1(state))
+    let docs_ui = state.config().api_docs.ui;
+
+    // the spec is collected from the same routes that are served, so the two
+    // can't drift apart
+    let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .nest("/api/v1", v1(state))
+        .split_for_parts();
+
+    let spec = Bytes::from(api.to_json().expect("the spec is serializable"));
+    let mut router = router.route(
+        "/api/v1/openapi.json",
+        get(|| async {
+            (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                )],
+                spec,
+            )
+        }),
+    );
+
+    if docs_ui {
+        router = router.merge(Redoc::with_url("/api/v1/docs", api));
+    }
+
+    router
 }
 
-fn v1(state: AppState) -> Router {
-    Router::new()
+fn v1(state: AppState) -> OpenApiRouter {
+    let max_body_size = state.config().limits.max_body_size;
+
+    OpenApiRouter::new()
+        .nest("/audit", audit::routes(state.clone()))
         .nest("/benchmarks", benchmarks::routes(state.clone()))
         .nest("/participants", participants::routes(state.clone()))
         .nest("/summaries", summaries::routes(state.clone()))
+        .layer(DefaultBodyLimit::max(max_body_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{config::Config, models::ModelManager};
+
+    async fn get(
+        config: Config,
+        pool: sqlx::PgPool,
+        uri: &str,
+    ) -> anyhow::Result<(StatusCode, Vec<u8>)> {
+        let state = AppState::new(config, ModelManager::from(pool));
+        let response = crate::routes::router(state)
+            .oneshot(Request::builder().uri(uri).body(Body::empty())?)
+            .await?;
+        let status = response.status();
+
+        Ok((
+            status,
+            to_bytes(response.into_body(), usize::MAX).await?.to_vec(),
+        ))
+    }
+
+    #[sqlx::test]
+    async fn serves_spec_of_every_route(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let (status, body) = get(Config::test(), pool, "/api/v1/openapi.json").await?;
+        assert_eq!(status, StatusCode::OK);
+
+        let spec: Value = serde_json::from_slice(&body)?;
+        let paths = &spec["paths"];
+        for (path, method) in [
+            ("/api/v1/audit", "get"),
+            ("/api/v1/benchmarks", "get"),
+            ("/api/v1/benchmarks", "post"),
+            ("/api/v1/benchmarks/{benchmark}", "get"),
+            ("/api/v1/participants", "get"),
+            ("/api/v1/participants", "post"),
+            ("/api/v1/participants/{year}/{participant}", "get"),
+            ("/api/v1/summaries", "get"),
+            ("/api/v1/summaries/generate", "post"),
+            ("/api/v1/summaries/{year}/{participant}", "get"),
+        ] {
+            assert!(
+                paths[path][method].is_object(),
+                "{method} {path} is missing"
+            );
+        }
+
+        // a single record or an array of them
+        let create = &spec["components"]["schemas"]["BenchmarkCreateRequest"];
+        assert_eq!(
+            create["oneOf"].as_array().map(Vec::len),
+            Some(2),
+            "{create}"
+        );
+
+        let filter = paths["/api/v1/benchmarks"]["get"]["parameters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|p| p["name"].as_str().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert!(filter.contains(&"participant"), "{filter:?}");
+        assert!(filter.contains(&"format"), "{filter:?}");
+
+        assert!(spec["components"]["securitySchemes"]["api_token"].is_object());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn docs_ui_is_opt_in(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let (status, _) = get(Config::test(), pool.clone(), "/api/v1/docs").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut config = Config::test();
+        config.api_docs.ui = true;
+
+        let (status, body) = get(config, pool, "/api/v1/docs").await?;
+        assert_eq!(status, StatusCode::OK);
+        // the spec is embedded in the page
+        assert!(String::from_utf8(body)?.contains("/api/v1/benchmarks"));
+
+        Ok(())
+    }
 }
@@ -1,54 +1,128 @@
 use axum::{
     extract::{Path, Query, State},
     middleware,
-    routing::{get, post},
-    Json, Router,
+    response::Response,
+    Extension, Json,
 };
 use serde::Deserialize;
+use tracing::instrument;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
 
+use super::format::{FormatParams, ListFormat};
 use crate::{
-    middleware::mw_require_auth,
-    models::{ModelManager, Participant, ParticipantBmc, ParticipantFilter},
+    error::ErrorEnvelope,
+    middleware::{mw_idempotency, mw_rate_limit, mw_require_auth},
+    models::{
+        AuditContext, BatchOutcome, ModelManager, Participant, ParticipantBmc, ParticipantFilter,
+    },
     server::AppState,
     Result,
 };
 
-pub fn routes(state: AppState) -> Router {
-    Router::new()
-        .route("/", post(create_participants))
-        .layer(middleware::from_fn(mw_require_auth))
-        .route("/", get(list_participants))
-        .route("/:year/:participant", get(get_participant))
+pub fn routes(state: AppState) -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(create_participants))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            mw_idempotency,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.config(),
+            mw_require_auth,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.write_limiter(),
+            mw_rate_limit,
+        ))
+        .routes(routes!(list_participants))
+        .routes(routes!(get_participant))
         .with_state(state)
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// A single participant, or an array of them.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 #[serde(untagged)]
+#[schema(as = ParticipantCreateRequest)]
 enum CreateRequest {
     One(Participant),
     Many(Vec<Participant>),
 }
 
+/// Register participants, replacing the language and repo of any already
+/// registered for the same year.
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "participants",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key"),
+    ),
+    request_body = CreateRequest,
+    responses(
+        (status = 200, description = "What happened to each record", body = [BatchOutcome<Participant>]),
+        (status = 400, description = "The batch is empty, or the idempotency key is malformed", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid token", body = ErrorEnvelope),
+        (status = 409, description = "A request with this idempotency key is in progress", body = ErrorEnvelope),
+        (status = 422, description = "Some records are invalid, or the idempotency key was used for a different request", body = ErrorEnvelope),
+        (status = 429, description = "Too many requests", body = ErrorEnvelope),
+    ),
+    security(("api_token" = [])),
+)]
+#[instrument(skip_all)]
 async fn create_participants(
     State(mm): State<ModelManager>,
+    Extension(ctx): Extension<AuditContext>,
     Json(payload): Json<CreateRequest>,
-) -> Result<Json<Vec<(i32, String)>>> {
+) -> Result<Json<Vec<BatchOutcome<Participant>>>> {
     let data = match payload {
         CreateRequest::One(v) => vec![v],
         CreateRequest::Many(v) => v,
     };
-    Ok(Json(
-        ParticipantBmc::batch_create_or_update(&mm, data).await?,
-    ))
+
+    let outcomes = ParticipantBmc::batch_create_or_update(&mm, &ctx, data).await?;
+
+    Ok(Json(outcomes))
 }
 
+/// List the participants matching the filter.
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "participants",
+    params(ParticipantFilter, FormatParams),
+    responses(
+        (status = 200, description = "The matching participants", content(
+            ([Participant] = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson"),
+        )),
+        (status = 400, description = "Unsupported format", body = ErrorEnvelope),
+    ),
+)]
+#[instrument(skip_all)]
 async fn list_participants(
     State(mm): State<ModelManager>,
+    format: ListFormat,
     Query(filter): Query<ParticipantFilter>,
-) -> Result<Json<Vec<Participant>>> {
-    Ok(Json(ParticipantBmc::list(&mm, filter).await?))
+) -> Result<Response> {
+    format.respond(ParticipantBmc::stream(&mm, filter)).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/{year}/{participant}",
+    tag = "participants",
+    params(
+        ("year" = i32, Path, description = "The year of the event"),
+        ("participant" = String, Path, description = "The participant's name"),
+    ),
+    responses(
+        (status = 200, description = "The participant", body = Participant),
+        (status = 404, description = "No such participant", body = ErrorEnvelope),
+    ),
+)]
+#[instrument(skip(mm))]
 async fn get_participant(
     State(mm): State<ModelManager>,
     Path((year, name)): Path<(i32, String)>,
@@ -62,15 +136,22 @@ mod tests {
         body::{to_bytes, Body},
         http::{self, Request, StatusCode},
     };
+    use serde_json::{json, Value};
     use tower::ServiceExt;
 
     use super::*;
+    use crate::config::Config;
+
+    // these tests only care about the handlers, not the spec
+    fn router(state: AppState) -> axum::Router {
+        super::routes(state).into()
+    }
 
     #[sqlx::test(fixtures("../../../fixtures/participants.sql"))]
     async fn test_create_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let create_args = Participant {
             year: 2023,
@@ -94,12 +175,14 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
 
         let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
-        let body: Vec<(i32, String)> = serde_json::from_slice(&raw_body)?;
-        assert_eq!(body.len(), 1);
-        assert_eq!(body, vec![(2023, "herp".to_string())]);
+        let body: Value = serde_json::from_slice(&raw_body)?;
+        assert_eq!(
+            body,
+            json!([{"index": 0, "status": "created", "id": {"year": 2023, "name": "herp"}}])
+        );
 
         // we need to get that created object back
-        let routes = super::routes(state);
+        let routes = router(state);
         let response = routes
             .oneshot(
                 Request::builder()
@@ -120,9 +203,9 @@ mod tests {
 
     #[sqlx::test]
     async fn test_create_batch_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let create_args = vec![
             Participant {
@@ -154,11 +237,13 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
 
         let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
-        let body: Vec<(i32, String)> = serde_json::from_slice(&raw_body)?;
-        assert_eq!(body.len(), 2);
+        let body: Value = serde_json::from_slice(&raw_body)?;
         assert_eq!(
             body,
-            vec![(2023, "herp".to_string()), (2023, "derp".to_string()),]
+            json!([
+                {"index": 0, "status": "created", "id": {"year": 2023, "name": "herp"}},
+                {"index": 1, "status": "created", "id": {"year": 2023, "name": "derp"}},
+            ])
         );
 
         Ok(())
@@ -166,9 +251,9 @@ mod tests {
 
     #[sqlx::test]
     async fn test_create_requires_auth(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let create_args = Participant {
             year: 2023,
@@ -196,9 +281,9 @@ mod tests {
 
     #[sqlx::test(fixtures("../../../fixtures/participants.sql"))]
     async fn test_list_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
@@ -216,9 +301,9 @@ mod tests {
 
     #[sqlx::test]
     async fn test_list_empty_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
@@ -236,8 +321,8 @@ mod tests {
 
     #[sqlx::test(fixtures("../../../fixtures/participants.sql"))]
     async fn test_list_filterd_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(
@@ -260,8 +345,8 @@ mod tests {
 
     #[sqlx::test(fixtures("../../../fixtures/participants.sql"))]
     async fn test_get_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(
@@ -284,8 +369,8 @@ mod tests {
 
     #[sqlx::test]
     async fn test_get_not_found(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(
@@ -298,6 +383,14 @@ mod tests {
 
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body: serde_json::Value = serde_json::from_slice(&raw_body)?;
+        assert_eq!(body["error"]["code"], "not_found");
+        assert_eq!(
+            body["error"]["details"],
+            serde_json::json!({"table": "participants", "id": {"year": 2025, "name": "bar"}})
+        );
+
         Ok(())
     }
 }
@@ -1,66 +1,137 @@
 use axum::{
     extract::{Path, Query, State},
     middleware,
-    routing::{get, post},
-    Json, Router,
+    response::Response,
+    Extension, Json,
+};
+use time::OffsetDateTime;
+use tracing::instrument;
+use utoipa_axum::{
+    router::{OpenApiRouter, UtoipaMethodRouterExt},
+    routes,
 };
 
+use super::format::{FormatParams, ListFormat};
 use crate::{
-    middleware::mw_require_auth,
-    models::{BenchmarkBmc, BenchmarkFilter, ModelManager, Summary, SummaryBmc, SummaryFilter},
+    error::ErrorEnvelope,
+    metrics::Metrics,
+    middleware::{mw_idempotency, mw_rate_limit, mw_require_auth},
+    models::{AuditContext, BatchOutcome, ModelManager, Summary, SummaryBmc, SummaryFilter},
     server::AppState,
     Result,
 };
 
-pub fn routes(state: AppState) -> Router {
-    Router::new()
-        .route("/generate", post(generate_summaries))
-        .layer(middleware::from_fn(mw_require_auth))
-        .route("/", get(list_summaies))
-        .route("/:year/:participant", get(get_summary))
+pub fn routes(state: AppState) -> OpenApiRouter {
+    OpenApiRouter::new()
+        // generation is both a write and expensive
+        .routes(
+            routes!(generate_summaries).layer(middleware::from_fn_with_state(
+                state.expensive_limiter(),
+                mw_rate_limit,
+            )),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            mw_idempotency,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.config(),
+            mw_require_auth,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.write_limiter(),
+            mw_rate_limit,
+        ))
+        .routes(routes!(list_summaies).layer(middleware::from_fn_with_state(
+            state.expensive_limiter(),
+            mw_rate_limit,
+        )))
+        .routes(routes!(get_summary))
         .with_state(state)
 }
 
+/// Generate the summaries for the year in the body from its benchmarks.
+#[utoipa::path(
+    post,
+    path = "/generate",
+    tag = "summaries",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key"),
+    ),
+    request_body(content = i32, description = "The year to summarize", example = 2023),
+    responses(
+        (status = 200, description = "What happened to each summary", body = [BatchOutcome<Summary>]),
+        (status = 400, description = "The idempotency key is malformed", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid token", body = ErrorEnvelope),
+        (status = 409, description = "A request with this idempotency key is in progress", body = ErrorEnvelope),
+        (status = 422, description = "The idempotency key was used for a different request", body = ErrorEnvelope),
+        (status = 429, description = "Too many requests", body = ErrorEnvelope),
+    ),
+    security(("api_token" = [])),
+)]
+#[instrument(skip_all)]
 async fn generate_summaries(
     State(mm): State<ModelManager>,
+    State(metrics): State<Metrics>,
+    Extension(ctx): Extension<AuditContext>,
     Json(payload): Json<i32>,
-) -> Result<Json<Vec<(i32, String)>>> {
-    let filter = BenchmarkFilter {
-        year: Some(payload),
-        ..Default::default()
-    };
+) -> Result<Json<Vec<BatchOutcome<Summary>>>> {
+    let _timer = metrics.time_summary_generation();
 
-    tracing::debug!("finding benchmarks for {payload}");
-    let benchmarks = BenchmarkBmc::list(&mm, filter).await?;
+    let started = OffsetDateTime::now_utc();
+    let summaries = SummaryBmc::generate(&mm, payload, None).await?;
 
     // if we didn't find anything, don't bother doing the next steps
-    if benchmarks.is_empty() {
+    if summaries.is_empty() {
         tracing::debug!("no benchmarks found");
         return Ok(Json(Vec::new()));
     }
 
-    let num_benches = benchmarks.len();
-
-    tracing::debug!("generating summaries from {} benchmarks", num_benches);
-    let summaries = Summary::from_benchmarks(benchmarks)?;
-    tracing::debug!(
-        "generated {} summaries from {} benchmarks",
-        summaries.len(),
-        num_benches
-    );
+    let outcomes = SummaryBmc::batch_create_or_update(&mm, &ctx, summaries).await?;
+    SummaryBmc::mark_generated(&mm, payload, started).await?;
 
-    Ok(Json(
-        SummaryBmc::batch_create_or_update(&mm, summaries).await?,
-    ))
+    Ok(Json(outcomes))
 }
 
+/// List the summaries matching the filter.
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "summaries",
+    params(SummaryFilter, FormatParams),
+    responses(
+        (status = 200, description = "The matching summaries", content(
+            ([Summary] = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson"),
+        )),
+        (status = 400, description = "Unsupported format", body = ErrorEnvelope),
+        (status = 429, description = "Too many requests", body = ErrorEnvelope),
+    ),
+)]
+#[instrument(skip_all)]
 async fn list_summaies(
     State(mm): State<ModelManager>,
+    format: ListFormat,
     Query(filter): Query<SummaryFilter>,
-) -> Result<Json<Vec<Summary>>> {
-    Ok(Json(SummaryBmc::list(&mm, filter).await?))
+) -> Result<Response> {
+    format.respond(SummaryBmc::stream(&mm, filter)).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/{year}/{participant}",
+    tag = "summaries",
+    params(
+        ("year" = i32, Path, description = "The year of the event"),
+        ("participant" = String, Path, description = "The participant's name"),
+    ),
+    responses(
+        (status = 200, description = "The summary", body = Summary),
+        (status = 404, description = "No such summary", body = ErrorEnvelope),
+    ),
+)]
+#[instrument(skip(mm))]
 async fn get_summary(
     State(mm): State<ModelManager>,
     Path((year, participant)): Path<(i32, String)>,
@@ -77,14 +148,20 @@ mod tests {
     use tower::ServiceExt;
 
     use super::*;
+    use crate::config::Config;
+
+    // these tests only care about the handlers, not the spec
+    fn router(state: AppState) -> axum::Router {
+        super::routes(state).into()
+    }
 
     // this fixture being benchmarks is intentional
     #[sqlx::test(fixtures("../../../fixtures/benchmarks.sql"))]
     async fn test_generate_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
         // check no summaries
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty())?)
@@ -97,7 +174,7 @@ mod tests {
         assert_eq!(body.len(), 0);
 
         // generate
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(
@@ -113,7 +190,7 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
 
         // check two summaries
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty())?)
@@ -130,9 +207,9 @@ mod tests {
 
     #[sqlx::test]
     async fn test_generate_requires_auth(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
 
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(
@@ -147,7 +224,7 @@ mod tests {
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 
         // and with wrong token
-        let routes = super::routes(state.clone());
+        let routes = router(state.clone());
 
         let response = routes
             .oneshot(
@@ -167,8 +244,8 @@ mod tests {
 
     #[sqlx::test(fixtures("../../../fixtures/summaries.sql"))]
     async fn test_list_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = super::routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
@@ -183,10 +260,43 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test(fixtures("../../../fixtures/summaries.sql"))]
+    async fn test_list_csv_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
+
+        let response = routes
+            .oneshot(
+                Request::builder()
+                    .uri("/?format=csv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let mut reader = csv::Reader::from_reader(raw_body.as_ref());
+
+        let headers = reader.headers()?.clone();
+        assert_eq!(headers.len(), 29);
+        assert_eq!(&headers[3], "day_1");
+        assert_eq!(&headers[27], "day_25");
+        assert_eq!(&headers[28], "total");
+
+        let body: Vec<Summary> = reader
+            .deserialize()
+            .collect::<std::result::Result<_, csv::Error>>()?;
+        assert_eq!(body.len(), 2);
+
+        Ok(())
+    }
+
     #[sqlx::test]
     async fn test_list_empty_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = super::routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
@@ -203,8 +313,8 @@ mod tests {
 
     #[sqlx::test(fixtures("../../../fixtures/summaries.sql"))]
     async fn test_list_filtered_ok(pool: sqlx::PgPool) -> anyhow::Result<()> {
-        let state = AppState::new(ModelManager::from(pool));
-        let routes = super::routes(state);
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
 
         let response = routes
             .oneshot(
@@ -224,4 +334,26 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test(fixtures("../../../fixtures/summaries.sql"))]
+    async fn test_get_not_found(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let routes = router(state);
+
+        let response = routes
+            .oneshot(Request::builder().uri("/2019/foo").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let raw_body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body: serde_json::Value = serde_json::from_slice(&raw_body)?;
+        assert_eq!(body["error"]["code"], "not_found");
+        assert_eq!(
+            body["error"]["details"],
+            serde_json::json!({"table": "summaries", "id": {"year": 2019, "participant": "foo"}})
+        );
+
+        Ok(())
+    }
 }
@@ -1,43 +1,384 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
 use clap::crate_version;
 use serde::Serialize;
+use serde_json::{json, Value};
 
-use crate::{models::ModelManager, server::AppState};
+use crate::{
+    config::Config,
+    models::{MigrationState, ModelManager, SummaryBmc},
+    server::{AppState, ShutdownSignal},
+};
 
 const VERSION: &str = crate_version!();
 
 pub fn routes(state: AppState) -> Router {
     Router::new()
-        .route("/health", get(health))
+        // kept for probes that predate the split
+        .route("/health", get(ready))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
         .with_state(state)
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+/// Ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum HealthStatus {
     Ok,
+    /// Still serving, but something needs attention.
     Degraded,
+    ShuttingDown,
     Error,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Health {
     version: &'static str,
     status: HealthStatus,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, Check>,
+}
+
+impl Health {
+    fn new(status: HealthStatus) -> Self {
+        Self {
+            version: VERSION,
+            status,
+            checks: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, name: &'static str, check: Check) {
+        self.status = self.status.max(check.status);
+        self.checks.insert(name, check);
+    }
 }
 
-async fn health(State(mm): State<ModelManager>) -> impl IntoResponse {
-    let mut h = Health {
-        version: VERSION,
-        status: HealthStatus::Ok,
+/// The outcome of one of the readiness checks.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+    status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<Value>,
+}
+
+impl Check {
+    fn new(status: HealthStatus, details: Value) -> Self {
+        Self {
+            status,
+            message: None,
+            details: Some(details),
+        }
+    }
+
+    fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            status: HealthStatus::Error,
+            message: Some(message.into()),
+            details: None,
+        }
+    }
+}
+
+/// Whether the process is up at all. This never touches the database, so an
+/// outage there doesn't get us restarted.
+async fn live(State(shutdown): State<ShutdownSignal>) -> impl IntoResponse {
+    let status = if shutdown.is_draining() {
+        HealthStatus::ShuttingDown
+    } else {
+        HealthStatus::Ok
+    };
+
+    (StatusCode::OK, Json(Health::new(status)))
+}
+
+/// Whether we should be sent traffic, with the outcome of each check.
+async fn ready(
+    State(mm): State<ModelManager>,
+    State(config): State<Arc<Config>>,
+    State(shutdown): State<ShutdownSignal>,
+) -> impl IntoResponse {
+    // still serving, but load balancers should stop sending traffic our way
+    if shutdown.is_draining() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Health::new(HealthStatus::ShuttingDown)),
+        );
+    }
+
+    let settings = &config.health;
+    let mut h = Health::new(HealthStatus::Ok);
+
+    let database = check_database(&mm, Duration::from_millis(settings.max_db_latency_ms)).await;
+    let reachable = database.status != HealthStatus::Error;
+    h.add("database", database);
+
+    // the rest would only fail the same way
+    if reachable {
+        h.add("migrations", check_migrations(&mm).await);
+        h.add("pool", check_pool(&mm, *settings.max_pool_usage));
+
+        if settings.summary_staleness > 0 {
+            let grace = Duration::from_secs(settings.summary_staleness);
+            h.add("summaries", check_summaries(&mm, grace).await);
+        }
+    }
+
+    let code = if h.status == HealthStatus::Error {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
     };
 
+    (code, Json(h))
+}
+
+async fn check_database(mm: &ModelManager, max_latency: Duration) -> Check {
+    let started = Instant::now();
+
     if let Err(e) = mm.check_connectivity().await {
-        h.status = HealthStatus::Error;
         tracing::error!("Cannot reach database: {:?}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(h));
+        return Check::error("cannot reach database");
+    }
+
+    let latency = started.elapsed();
+    let details = json!({
+        "latency_ms": latency.as_millis() as u64,
+        "max_latency_ms": max_latency.as_millis() as u64,
+    });
+
+    if latency > max_latency {
+        Check::new(HealthStatus::Degraded, details).with_message("database is slow to respond")
+    } else {
+        Check::new(HealthStatus::Ok, details)
+    }
+}
+
+async fn check_migrations(mm: &ModelManager) -> Check {
+    let migrations = match mm.migration_status().await {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::error!("Cannot check migrations: {:?}", e);
+            return Check::error("cannot check migrations");
+        }
+    };
+
+    let count = |state| migrations.iter().filter(|m| m.state == state).count();
+
+    let pending = count(MigrationState::Pending);
+    let failed = count(MigrationState::Failed);
+    // a newer version may have been deployed alongside us, so these are not
+    // fatal on their own
+    let mismatched = count(MigrationState::ChecksumMismatch);
+    let unknown = count(MigrationState::Unknown);
+
+    let details = json!({
+        "pending": pending,
+        "failed": failed,
+        "checksum_mismatch": mismatched,
+        "unknown": unknown,
+    });
+
+    if pending + failed > 0 {
+        Check::new(HealthStatus::Error, details)
+            .with_message("the schema is behind this version of the application")
+    } else if mismatched + unknown > 0 {
+        Check::new(HealthStatus::Degraded, details)
+            .with_message("the schema does not match this version of the application")
+    } else {
+        Check::new(HealthStatus::Ok, details)
+    }
+}
+
+fn check_pool(mm: &ModelManager, max_usage: f64) -> Check {
+    let pool = mm.pool_stats();
+    let in_use = pool.size as usize - pool.idle.min(pool.size as usize);
+    let usage = in_use as f64 / pool.max.max(1) as f64;
+
+    let details = json!({
+        "in_use": in_use,
+        "idle": pool.idle,
+        "max": pool.max,
+    });
+
+    if usage > max_usage {
+        Check::new(HealthStatus::Degraded, details).with_message("connection pool is saturated")
+    } else {
+        Check::new(HealthStatus::Ok, details)
+    }
+}
+
+async fn check_summaries(mm: &ModelManager, grace: Duration) -> Check {
+    let years = match SummaryBmc::stale_years(mm, grace).await {
+        Ok(years) => years,
+        Err(e) => {
+            tracing::error!("Cannot check summaries: {:?}", e);
+            return Check::error("cannot check summaries");
+        }
+    };
+
+    if years.is_empty() {
+        Check::new(HealthStatus::Ok, json!({ "stale_years": years }))
+    } else {
+        Check::new(HealthStatus::Degraded, json!({ "stale_years": years }))
+            .with_message("summaries have not been generated since benchmarks changed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::models::{AuditContext, BenchmarkBmc, BenchmarkCreate};
+
+    // through the full router, so the error middleware is in the way too
+    async fn get(state: AppState, uri: &str) -> anyhow::Result<(StatusCode, Value)> {
+        let response = crate::routes::router(state)
+            .oneshot(Request::builder().uri(uri).body(Body::empty())?)
+            .await?;
+        let status = response.status();
+        let body = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await?)?;
+
+        Ok((status, body))
+    }
+
+    #[sqlx::test]
+    async fn reports_shutting_down(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+
+        let (status, _) = get(state.clone(), "/health").await?;
+        assert_eq!(status, StatusCode::OK);
+
+        // as soon as the signal arrives, before the listeners stop
+        state.shutdown().drain();
+
+        let (status, body) = get(state.clone(), "/health").await?;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "shuttingDown");
+
+        // still alive though
+        let (status, body) = get(state, "/health/live").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "shuttingDown");
+
+        Ok(())
     }
 
-    (StatusCode::OK, Json(h))
+    #[sqlx::test]
+    async fn reports_each_check(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+
+        let (status, body) = get(state.clone(), "/health/ready").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        for check in ["database", "migrations", "pool", "summaries"] {
+            assert_eq!(body["checks"][check]["status"], "ok", "{check}: {body}");
+        }
+
+        let (_, body) = get(state, "/health/live").await?;
+        assert_eq!(body, json!({ "version": VERSION, "status": "ok" }));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn identical_regeneration_is_not_stale(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+        let ctx = AuditContext::new("test", "POST /");
+
+        let mut config = Config::test();
+        config.health.summary_staleness = 1;
+        let state = AppState::new(config, mm.clone());
+
+        let generate = || async {
+            let response = crate::routes::router(state.clone())
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/api/v1/summaries/generate")
+                        .header("content-type", "application/json")
+                        .header("authorization", "Bearer sandcastle")
+                        .body(Body::from("2023"))?,
+                )
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+            anyhow::Ok(())
+        };
+
+        let mut benchmark = BenchmarkCreate {
+            year: 2023,
+            day: 1,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            ..Default::default()
+        };
+        BenchmarkBmc::batch_create_or_update(&mm, &ctx, [benchmark.clone()]).await?;
+        generate().await?;
+
+        // the summaries only use the mean, so they come out the same
+        benchmark.stddev = 0.5;
+        BenchmarkBmc::batch_create_or_update(&mm, &ctx, [benchmark]).await?;
+        generate().await?;
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (status, body) = get(state, "/health/ready").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["checks"]["summaries"]["status"], "ok", "{body}");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn degraded_when_slow_or_stale(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mm = ModelManager::from(pool);
+
+        let data = [BenchmarkCreate {
+            year: 2023,
+            day: 1,
+            input: "input-foo".into(),
+            participant: "foo".into(),
+            language: "rust".into(),
+            ..Default::default()
+        }];
+        let ctx = AuditContext::new("test", "POST /");
+        BenchmarkBmc::batch_create_or_update(&mm, &ctx, data).await?;
+
+        let mut config = Config::test();
+        config.health.max_db_latency_ms = 0;
+        config.health.summary_staleness = 1;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (status, body) = get(AppState::new(config, mm), "/health/ready").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["checks"]["database"]["status"], "degraded");
+        assert_eq!(body["checks"]["summaries"]["status"], "degraded");
+        assert_eq!(
+            body["checks"]["summaries"]["details"]["stale_years"],
+            json!([2023])
+        );
+        assert_eq!(body["checks"]["migrations"]["status"], "ok");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,88 @@
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use prometheus::TEXT_FORMAT;
+
+use crate::{metrics::Metrics, models::ModelManager, server::AppState, Result};
+
+pub fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+async fn metrics(
+    State(metrics): State<Metrics>,
+    State(mm): State<ModelManager>,
+) -> Result<impl IntoResponse> {
+    Ok((
+        [(header::CONTENT_TYPE, TEXT_FORMAT)],
+        metrics.render(&mm).await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+
+    async fn get(app: Router, uri: &str) -> anyhow::Result<(StatusCode, String)> {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty())?)
+            .await?;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+
+        Ok((status, String::from_utf8(body.to_vec())?))
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/benchmarks.sql"))]
+    async fn reports_requests_and_benchmarks(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let state = AppState::new(Config::test(), ModelManager::from(pool));
+        let app = crate::routes::router(state);
+
+        let (status, _) = get(app.clone(), "/api/v1/benchmarks/1000").await?;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = get(app, "/metrics").await?;
+        assert_eq!(status, StatusCode::OK);
+
+        for line in [
+            r#"aoc_http_requests_total{method="GET",route="/api/v1/benchmarks/:benchmark",status="200"} 1"#,
+            r#"aoc_benchmarks{year="2023"} 4"#,
+            r#"aoc_db_pool_max_connections "#,
+        ] {
+            assert!(body.contains(line), "{line} missing from:\n{body}");
+        }
+
+        // the scrape itself isn't counted
+        assert!(!body.contains(r#"route="/metrics""#));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn moves_to_its_own_port(pool: sqlx::PgPool) -> anyhow::Result<()> {
+        let mut config = Config::test();
+        config.metrics.port = Some(9090);
+        let state = AppState::new(config, ModelManager::from(pool));
+
+        let (status, _) = get(crate::routes::router(state.clone()), "/metrics").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = get(crate::routes::metrics_router(state.clone()), "/metrics").await?;
+        assert_eq!(status, StatusCode::OK);
+
+        // errors there are identified like anywhere else
+        let (status, body) = get(crate::routes::metrics_router(state), "/nope").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let body: serde_json::Value = serde_json::from_str(&body)?;
+        assert!(body["error"]["request_id"].is_string(), "{body}");
+
+        Ok(())
+    }
+}
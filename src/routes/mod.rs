@@ -1,21 +1,47 @@
-use axum::Router;
+use axum::{middleware, Router};
 use tower_http::trace::{self, TraceLayer};
 use tracing::Level;
 
-use crate::server::AppState;
+use crate::{
+    middleware::{mw_request_id, mw_response_map, mw_track_metrics},
+    server::AppState,
+    telemetry,
+};
 
 mod api;
 mod health;
+mod metrics;
 
 pub fn router(state: AppState) -> Router {
-    Router::new()
+    let config = state.config();
+
+    let mut router = Router::new()
         .merge(api::routes(state.clone()))
+        .route_layer(middleware::from_fn_with_state(
+            state.metrics(),
+            mw_track_metrics,
+        ))
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                .make_span_with(telemetry::make_span)
                 .on_response(trace::DefaultOnResponse::new().level(Level::INFO))
                 .on_failure(trace::DefaultOnFailure::new().level(Level::ERROR)),
         )
         // define after tracing so no traces on health routes
-        .merge(health::routes(state))
+        .merge(health::routes(state.clone()));
+
+    if config.metrics.enabled && config.metrics.port.is_none() {
+        router = router.merge(metrics::routes(state));
+    }
+
+    router
+        .layer(middleware::from_fn(mw_response_map))
+        .layer(middleware::from_fn(mw_request_id))
+}
+
+/// The routes served on the separate metrics port, when there is one.
+pub fn metrics_router(state: AppState) -> Router {
+    metrics::routes(state)
+        .layer(middleware::from_fn(mw_response_map))
+        .layer(middleware::from_fn(mw_request_id))
 }
@@ -0,0 +1,251 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tower::ServiceExt;
+
+use super::{
+    shutdown::{ConnectionWatcher, Connections, ShutdownSignal},
+    tls::TlsAcceptor,
+};
+use crate::config::ListenAddr;
+
+/// A bound socket the server accepts connections on.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(unix::SocketFile),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => Ok(Self::Unix(unix::SocketFile::bind(path).await?)),
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix domain sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// Terminate TLS on this listener, if it is a TCP one.
+    pub fn with_tls(self, acceptor: TlsAcceptor) -> Self {
+        match self {
+            Listener::Tcp(listener) => Listener::Tls(listener, acceptor),
+            other => other,
+        }
+    }
+
+    /// Accept connections until `shutdown` is triggered, handing each one to
+    /// `app` on its own task, tracked by `connections`.
+    pub async fn serve(self, app: Router, shutdown: ShutdownSignal, connections: Connections) {
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.triggered() => break,
+                _ = self.accept(&app, &connections) => {}
+            }
+        }
+    }
+
+    async fn accept(&self, app: &Router, connections: &Connections) {
+        match self {
+            Listener::Tcp(listener) => match listener.accept().await {
+                // we need the connect info for rate limiting by client address
+                Ok((stream, addr)) => {
+                    tokio::spawn(serve_connection(
+                        stream,
+                        Some(addr),
+                        app.clone(),
+                        connections.watcher(),
+                    ));
+                }
+                Err(e) => accept_failed(e).await,
+            },
+            Listener::Tls(listener, acceptor) => match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let (acceptor, app, watcher) =
+                        (acceptor.clone(), app.clone(), connections.watcher());
+
+                    // the handshake happens off the accept loop, so a slow
+                    // client doesn't hold up everyone else
+                    tokio::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => serve_connection(stream, Some(addr), app, watcher).await,
+                            Err(e) => tracing::debug!("tls handshake with {addr} failed: {e}"),
+                        }
+                    });
+                }
+                Err(e) => accept_failed(e).await,
+            },
+            #[cfg(unix)]
+            Listener::Unix(socket) => match socket.listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_connection(
+                        stream,
+                        None,
+                        app.clone(),
+                        connections.watcher(),
+                    ));
+                }
+                Err(e) => accept_failed(e).await,
+            },
+        }
+    }
+}
+
+// errors for a single connection don't matter, but for anything else, like
+// running out of file descriptors, we back off rather than spin
+async fn accept_failed(e: io::Error) {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset => {}
+        _ => {
+            tracing::error!("failed to accept connection: {e}");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+async fn serve_connection<I>(
+    io: I,
+    remote: Option<SocketAddr>,
+    app: Router,
+    watcher: ConnectionWatcher,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+        if let Some(addr) = remote {
+            req.extensions_mut().insert(ConnectInfo(addr));
+        }
+
+        app.clone().oneshot(req)
+    });
+
+    let builder = Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+
+    // on shutdown, in-flight requests finish but nothing new is accepted
+    watcher.watch(connection).await;
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        io,
+        path::{Path, PathBuf},
+    };
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// A listening Unix domain socket that removes its file when dropped.
+    #[derive(Debug)]
+    pub struct SocketFile {
+        pub listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl SocketFile {
+        pub async fn bind(path: &Path) -> io::Result<Self> {
+            // a file left behind by a server that didn't exit cleanly would
+            // fail the bind, but we must not take over one that's still in use
+            if path.exists() {
+                if UnixStream::connect(path).await.is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use", path.display()),
+                    ));
+                }
+
+                std::fs::remove_file(path)?;
+            }
+
+            Ok(Self {
+                listener: UnixListener::bind(path)?,
+                path: path.to_path_buf(),
+            })
+        }
+    }
+
+    impl Drop for SocketFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+
+    use super::*;
+
+    fn app() -> Router {
+        Router::new().route(
+            "/",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.ip().to_string() }),
+        )
+    }
+
+    #[tokio::test]
+    async fn serves_tcp() -> anyhow::Result<()> {
+        let listener = Listener::bind(&ListenAddr::Tcp("[::1]:0".parse()?)).await?;
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let addr = tcp.local_addr()?;
+
+        tokio::spawn(listener.serve(app(), ShutdownSignal::default(), Connections::default()));
+
+        let body = reqwest::get(format!("http://{addr}/"))
+            .await?
+            .text()
+            .await?;
+        assert_eq!(body, "::1");
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_lifecycle() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("aoc-web-{}.sock", uuid::Uuid::new_v4()));
+        let addr = ListenAddr::Unix(path.clone());
+
+        let listener = Listener::bind(&addr).await?;
+        assert!(path.exists());
+
+        let shutdown = ShutdownSignal::default();
+        let listener =
+            tokio::spawn(listener.serve(Router::new(), shutdown.clone(), Connections::default()));
+        tokio::task::yield_now().await;
+
+        // still being served
+        assert!(Listener::bind(&addr).await.is_err());
+
+        shutdown.trigger();
+        listener.await?;
+        assert!(!path.exists());
+
+        // a stale file is replaced
+        std::fs::write(&path, "")?;
+        drop(Listener::bind(&addr).await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,372 @@
+mod listener;
+mod shutdown;
+mod tls;
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+
+use self::shutdown::Connections;
+pub use self::{listener::Listener, shutdown::ShutdownSignal, tls::TlsAcceptor};
+use crate::{
+    config::{Config, Cors},
+    metrics::Metrics,
+    middleware::{RateLimiter, REQUEST_ID_HEADER},
+    models::{IdempotencyBmc, MigrationState, ModelManager},
+    routes,
+    telemetry::Telemetry,
+};
+use axum::{
+    extract::FromRef,
+    http::{HeaderName, HeaderValue},
+    Router,
+};
+use futures::future::join_all;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+// expired keys are already ignored by claims, so this only bounds the table size
+const IDEMPOTENCY_PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, FromRef)]
+pub struct AppState {
+    config: Arc<Config>,
+    mm: ModelManager,
+    shutdown: ShutdownSignal,
+    metrics: Metrics,
+    #[from_ref(skip)]
+    write_limiter: RateLimiter,
+    #[from_ref(skip)]
+    expensive_limiter: RateLimiter,
+}
+
+impl AppState {
+    pub fn new(config: Config, mm: ModelManager) -> Self {
+        let limits = &config.limits;
+
+        Self {
+            write_limiter: RateLimiter::new(limits.write, &limits.trusted_proxies),
+            expensive_limiter: RateLimiter::new(limits.expensive, &limits.trusted_proxies),
+            config: Arc::new(config),
+            mm,
+            shutdown: ShutdownSignal::default(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.clone()
+    }
+
+    pub fn shutdown(&self) -> ShutdownSignal {
+        self.shutdown.clone()
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// The limiter shared by all endpoints that modify data.
+    pub fn write_limiter(&self) -> RateLimiter {
+        self.write_limiter.clone()
+    }
+
+    /// The limiter shared by all endpoints that are expensive to serve.
+    pub fn expensive_limiter(&self) -> RateLimiter {
+        self.expensive_limiter.clone()
+    }
+}
+
+pub async fn serve(config: Config) -> anyhow::Result<()> {
+    let telemetry = Telemetry::init(&config.log, &config.tracing)?;
+
+    let mm = ModelManager::new(&config.db).await?;
+    let state = AppState::new(config, mm);
+
+    tracing::info!("initialized app state");
+
+    if state.config.db.migrate_on_startup {
+        tracing::info!("applying migrations");
+        state.mm.migrate().await?;
+    } else {
+        let pending = state
+            .mm
+            .migration_status()
+            .await?
+            .into_iter()
+            .filter(|m| m.state == MigrationState::Pending)
+            .count();
+
+        if pending > 0 {
+            tracing::warn!("skipping {pending} pending migration(s) at startup");
+        }
+    }
+
+    if state.config.idempotency.window > 0 {
+        tokio::spawn(purge_idempotency_keys(
+            state.mm.clone(),
+            Duration::from_secs(state.config.idempotency.window),
+        ));
+    }
+
+    let tls = match &state.config.tls {
+        Some(tls) => {
+            let acceptor = TlsAcceptor::new(tls).context("could not set up tls")?;
+            acceptor.spawn_reloader();
+            Some(acceptor)
+        }
+        None => None,
+    };
+
+    let app = service(state.clone());
+    let mut listeners = Vec::new();
+
+    for addr in state.config.listen_addrs() {
+        let mut listener = Listener::bind(&addr)
+            .await
+            .with_context(|| format!("could not listen on {addr}"))?;
+
+        if let Some(tls) = &tls {
+            listener = listener.with_tls(tls.clone());
+        }
+
+        tracing::info!("listening on {addr}");
+        listeners.push((listener, app.clone()));
+    }
+
+    let metrics = routes::metrics_router(state.clone());
+
+    for addr in state.config.metrics_addrs() {
+        let listener = Listener::bind(&addr)
+            .await
+            .with_context(|| format!("could not serve metrics on {addr}"))?;
+
+        tracing::info!("serving metrics on {addr}");
+        listeners.push((listener, metrics.clone()));
+    }
+
+    let shutdown = state.shutdown();
+    let settings = state.config.shutdown.clone();
+    let mm = state.mm.clone();
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+
+        async move {
+            shutdown::os_signal().await;
+            shutdown.drain();
+            tracing::info!(
+                "shutting down, serving for another {}s",
+                settings.grace_period
+            );
+
+            tokio::time::sleep(Duration::from_secs(settings.grace_period)).await;
+            shutdown.trigger();
+        }
+    });
+
+    run(
+        listeners,
+        shutdown,
+        Duration::from_secs(settings.drain_timeout),
+    )
+    .await;
+
+    mm.close().await;
+    tracing::info!("shut down");
+    telemetry.shutdown().await;
+
+    Ok(())
+}
+
+/// Serve every listener with its app until `shutdown` is triggered, then give
+/// the open connections up to `drain_timeout` to finish what they are doing.
+async fn run(
+    listeners: Vec<(Listener, Router)>,
+    shutdown: ShutdownSignal,
+    drain_timeout: Duration,
+) {
+    let connections = Connections::default();
+
+    join_all(
+        listeners
+            .into_iter()
+            .map(|(l, app)| l.serve(app, shutdown.clone(), connections.clone())),
+    )
+    .await;
+
+    connections.drain(drain_timeout).await;
+}
+
+async fn purge_idempotency_keys(mm: ModelManager, window: Duration) {
+    let mut interval = tokio::time::interval(IDEMPOTENCY_PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match IdempotencyBmc::purge_expired(&mm, window).await {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("purged {n} expired idempotency key(s)"),
+            Err(e) => tracing::warn!("could not purge idempotency keys: {e:?}"),
+        }
+    }
+}
+
+// separated to allow testing without the server, and not allow for not reaching
+// into the router module directly by an extenal caller
+pub fn service(state: AppState) -> Router {
+    let cors = cors_layer(&state.config.cors);
+
+    routes::router(state).layer(cors)
+}
+
+fn cors_layer(config: &Cors) -> CorsLayer {
+    let origins = if config.allows_any_origin() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o).expect("origins are validated on load")),
+        )
+    };
+
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(config.allowed_methods.clone())
+        .allow_headers(config.allowed_headers.clone())
+        .max_age(Duration::from_secs(config.max_age))
+        // so pages can report it alongside errors
+        .expose_headers([HeaderName::from_static(REQUEST_ID_HEADER)])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::ListenAddr;
+
+    async fn slow() -> &'static str {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        "done"
+    }
+
+    async fn start(drain_timeout: Duration) -> anyhow::Result<(String, ShutdownSignal)> {
+        let listener = Listener::bind(&ListenAddr::Tcp("127.0.0.1:0".parse()?)).await?;
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let url = format!("http://{}/", tcp.local_addr()?);
+
+        let shutdown = ShutdownSignal::default();
+        tokio::spawn(run(
+            vec![(listener, Router::new().route("/", get(slow)))],
+            shutdown.clone(),
+            drain_timeout,
+        ));
+
+        Ok((url, shutdown))
+    }
+
+    #[tokio::test]
+    async fn drains_in_flight_requests() -> anyhow::Result<()> {
+        let (url, shutdown) = start(Duration::from_secs(5)).await?;
+
+        let in_flight = tokio::spawn(reqwest::get(url.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.trigger();
+
+        let response = in_flight.await??;
+        assert_eq!(response.text().await?, "done");
+
+        // no longer accepting
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(reqwest::get(url).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_requests_after_timeout() -> anyhow::Result<()> {
+        let (url, shutdown) = start(Duration::from_millis(50)).await?;
+
+        let in_flight = tokio::spawn(reqwest::get(url));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        shutdown.trigger();
+
+        assert!(in_flight.await?.is_err());
+        assert!(started.elapsed() < Duration::from_millis(300));
+
+        Ok(())
+    }
+
+    async fn preflight(cors: &Cors, origin: &str) -> anyhow::Result<axum::response::Response> {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(cors_layer(cors));
+
+        Ok(app
+            .oneshot(
+                Request::builder()
+                    .method(Method::OPTIONS)
+                    .uri("/")
+                    .header(header::ORIGIN, origin)
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "DELETE")
+                    .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                    .body(Body::empty())?,
+            )
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn cors_allows_configured_origins() -> anyhow::Result<()> {
+        let cors = Cors {
+            allowed_origins: vec!["https://dash.example.com".into()],
+            ..Default::default()
+        };
+
+        let response = preflight(&cors, "https://dash.example.com").await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dash.example.com"
+        );
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()?
+            .contains("DELETE"));
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "3600");
+
+        let response = preflight(&cors, "https://evil.example.com").await?;
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cors_any_origin() -> anyhow::Result<()> {
+        let cors = Cors {
+            allowed_origins: vec!["*".into()],
+            ..Default::default()
+        };
+
+        let response = preflight(&cors, "https://anywhere.example.com").await?;
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+        Ok(())
+    }
+}
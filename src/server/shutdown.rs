@@ -0,0 +1,150 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hyper_util::server::graceful::{GracefulConnection, GracefulShutdown, Watcher};
+use tokio::sync::watch;
+
+/// Shared by everything that needs to know when the server is shutting down,
+/// like the listeners and the health check.
+///
+/// Shutting down starts with draining, where we keep serving but tell load
+/// balancers to stop sending traffic, and ends with the trigger that stops
+/// the listeners.
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal {
+    stop: Arc<watch::Sender<bool>>,
+    draining: Arc<AtomicBool>,
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        Self {
+            stop: Arc::new(watch::channel(false).0),
+            draining: Arc::default(),
+        }
+    }
+}
+
+impl ShutdownSignal {
+    /// Start reporting that we are shutting down, while still serving.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether we are on the way out, draining or stopped.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed) || self.is_triggered()
+    }
+
+    /// Stop accepting connections.
+    pub fn trigger(&self) {
+        self.drain();
+        self.stop.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    /// Resolves once the signal has been triggered.
+    pub async fn triggered(&self) {
+        let mut rx = self.stop.subscribe();
+
+        while !*rx.borrow_and_update() {
+            // the sender lives as long as we do, so this can't fail
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Tracks the open connections, so they can be drained on shutdown.
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    graceful: Arc<GracefulShutdown>,
+    abort: ShutdownSignal,
+}
+
+impl Connections {
+    /// A handle for a single new connection to be tracked with.
+    pub fn watcher(&self) -> ConnectionWatcher {
+        ConnectionWatcher {
+            watcher: self.graceful.watcher(),
+            abort: self.abort.clone(),
+        }
+    }
+
+    /// Ask every connection to finish its in-flight requests and close,
+    /// dropping the ones that are still open after `timeout`.
+    ///
+    /// Nothing else may be holding on to the tracker by now.
+    pub async fn drain(self, timeout: Duration) {
+        let graceful =
+            Arc::into_inner(self.graceful).expect("connections are still being accepted");
+        tracing::info!("draining {} connection(s)", graceful.count());
+
+        if tokio::time::timeout(timeout, graceful.shutdown())
+            .await
+            .is_err()
+        {
+            tracing::warn!("connections did not drain within {timeout:?}, dropping them");
+            self.abort.trigger();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionWatcher {
+    watcher: Watcher,
+    abort: ShutdownSignal,
+}
+
+impl ConnectionWatcher {
+    /// Serve `connection` until it is done, or until it is dropped for taking
+    /// too long to drain.
+    pub async fn watch<C>(self, connection: C)
+    where
+        C: GracefulConnection,
+        C::Error: std::fmt::Display,
+    {
+        tokio::select! {
+            result = self.watcher.watch(connection) => {
+                if let Err(e) = result {
+                    tracing::debug!("connection closed with error: {e}");
+                }
+            }
+            _ = self.abort.triggered() => {}
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn os_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::{self, sign::any_supported_type},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+};
+
+use crate::config::Tls;
+
+// a client that never finishes the handshake shouldn't hold on to a task
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Terminates TLS with a certificate that is reloaded when its files change.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+    certs: Arc<ReloadingCert>,
+}
+
+impl std::fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsAcceptor")
+            .field("config", &self.certs.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsAcceptor {
+    pub fn new(config: &Tls) -> anyhow::Result<Self> {
+        let certs = Arc::new(ReloadingCert::new(config.clone())?);
+
+        let mut server_config =
+            ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_cert_resolver(certs.clone());
+
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+            certs,
+        })
+    }
+
+    pub async fn accept<IO>(&self, stream: IO) -> std::io::Result<TlsStream<IO>>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream))
+            .await
+            .map_err(|_| std::io::ErrorKind::TimedOut)?
+    }
+
+    /// Check for changed files every `reload_interval` seconds, for as long as
+    /// the acceptor is in use.
+    pub fn spawn_reloader(&self) {
+        let interval = self.certs.config.reload_interval;
+        if interval == 0 {
+            return;
+        }
+
+        let certs = Arc::downgrade(&self.certs);
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(interval));
+
+            loop {
+                ticks.tick().await;
+
+                let Some(certs) = certs.upgrade() else {
+                    break;
+                };
+
+                // the previous certificate keeps being served, so this isn't
+                // fatal, but it does need fixing before it expires
+                match certs.reload_if_changed() {
+                    Ok(true) => tracing::info!("reloaded tls certificate"),
+                    Ok(false) => {}
+                    Err(e) => tracing::error!("failed to reload tls certificate: {e:#}"),
+                }
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+struct ReloadingCert {
+    config: Tls,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl ReloadingCert {
+    fn new(config: Tls) -> anyhow::Result<Self> {
+        let modified = last_modified(&config);
+        let current = load(&config)?;
+
+        Ok(Self {
+            config,
+            current: RwLock::new(current),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Load the certificate again if either file changed since the last time,
+    /// returning whether it did.
+    fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let modified = last_modified(&self.config);
+        let mut last = self.modified.lock().expect("tls reload lock poisoned");
+
+        if modified == *last {
+            return Ok(false);
+        }
+
+        // only remember the change once it loads, so a half written file is
+        // retried on the next check
+        let certified = load(&self.config)?;
+        *self.current.write().expect("tls cert lock poisoned") = certified;
+        *last = modified;
+
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|c| c.clone())
+    }
+}
+
+fn last_modified(config: &Tls) -> Option<SystemTime> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    modified(&config.cert).max(modified(&config.key))
+}
+
+fn load(config: &Tls) -> anyhow::Result<Arc<CertifiedKey>> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("could not read {}", path.display()))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate in {}", config.cert.display()))?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates in {}", config.cert.display()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(&config.key)?)
+        .with_context(|| format!("invalid private key in {}", config.key.display()))?
+        .ok_or_else(|| anyhow!("no private key in {}", config.key.display()))?;
+
+    let key = any_supported_type(&key)
+        .with_context(|| format!("unsupported private key in {}", config.key.display()))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use axum::{routing::get, Router};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::ListenAddr,
+        server::{shutdown::Connections, Listener, ShutdownSignal},
+    };
+
+    /// Writes a new self signed certificate for localhost, returning it.
+    fn write_cert(config: &Tls) -> anyhow::Result<reqwest::Certificate> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+
+        fs::write(&config.cert, cert.cert.pem())?;
+        fs::write(&config.key, cert.key_pair.serialize_pem())?;
+
+        Ok(reqwest::Certificate::from_pem(cert.cert.pem().as_bytes())?)
+    }
+
+    fn client(root: reqwest::Certificate) -> anyhow::Result<reqwest::Client> {
+        Ok(reqwest::Client::builder()
+            .add_root_certificate(root)
+            .tls_built_in_root_certs(false)
+            .build()?)
+    }
+
+    #[tokio::test]
+    async fn serves_and_reloads() -> anyhow::Result<()> {
+        let dir: PathBuf = std::env::temp_dir().join(format!("aoc-web-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+
+        let config = Tls {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            reload_interval: 0,
+        };
+        let first = write_cert(&config)?;
+
+        let acceptor = TlsAcceptor::new(&config)?;
+        let listener = Listener::bind(&ListenAddr::Tcp("127.0.0.1:0".parse()?))
+            .await?
+            .with_tls(acceptor.clone());
+        let Listener::Tls(tcp, _) = &listener else {
+            unreachable!()
+        };
+        let url = format!("https://localhost:{}/", tcp.local_addr()?.port());
+
+        tokio::spawn(listener.serve(
+            Router::new().route("/", get(|| async { "secure" })),
+            ShutdownSignal::default(),
+            Connections::default(),
+        ));
+
+        let body = client(first.clone())?
+            .get(&url)
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert_eq!(body, "secure");
+
+        assert!(!acceptor.certs.reload_if_changed()?);
+
+        // make sure the modification time moves on
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = write_cert(&config)?;
+        assert!(acceptor.certs.reload_if_changed()?);
+
+        assert!(client(first)?.get(&url).send().await.is_err());
+        assert!(client(second)?.get(&url).send().await.is_ok());
+
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_bad_files() {
+        let config = Tls {
+            cert: "/does/not/exist.pem".into(),
+            key: "/does/not/exist.key".into(),
+            reload_interval: 0,
+        };
+
+        let err = TlsAcceptor::new(&config).unwrap_err();
+        assert!(err.to_string().contains("/does/not/exist.pem"));
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::Context;
+use axum::http::Request;
+use opentelemetry::{propagation::TextMapPropagator, trace::TracerProvider as _, KeyValue};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, TracerProvider},
+    Resource,
+};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, Layer,
+};
+
+use crate::{
+    config::{self, LogFormat},
+    middleware::REQUEST_ID_HEADER,
+};
+
+/// Sends the spans recorded via `tracing` to a collector, if one is
+/// configured.
+#[derive(Debug)]
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    /// Install the global subscriber, which logs to stdout in the configured
+    /// format and exports spans when `config` has an endpoint.
+    pub fn init(log: &config::Log, config: &config::Tracing) -> anyhow::Result<Self> {
+        let provider = config
+            .traces_endpoint()
+            .map(|_| provider(config))
+            .transpose()?;
+
+        let json = log.format == LogFormat::Json;
+
+        tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| "aoc_web=debug,tower_http=debug".into()),
+            )
+            .with((!json).then(tracing_subscriber::fmt::layer))
+            .with(json.then(|| tracing_subscriber::fmt::layer().json()))
+            .with(provider.as_ref().map(layer))
+            .init();
+
+        if let Some(endpoint) = config.traces_endpoint() {
+            tracing::info!("exporting traces to {endpoint}");
+        }
+
+        Ok(Self { provider })
+    }
+
+    /// Export whatever spans are still buffered.
+    pub async fn shutdown(self) {
+        let Some(provider) = self.provider else {
+            return;
+        };
+
+        // this blocks until the exporter is done
+        let result = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+
+        if let Ok(Err(e)) = result {
+            tracing::warn!("could not export the remaining traces: {e}");
+        }
+    }
+}
+
+fn provider(config: &config::Tracing) -> anyhow::Result<TracerProvider> {
+    let endpoint = config
+        .traces_endpoint()
+        .context("no endpoint to export traces to")?;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .context("could not set up trace export")?;
+
+    // follow the caller's decision, so we don't leave holes in their traces
+    let sampler =
+        Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(*config.sampling_ratio)));
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(sampler)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]))
+        .build())
+}
+
+fn layer<S>(provider: &TracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+}
+
+/// The span for a request, continuing the trace from its W3C `traceparent`
+/// header, if it has one. Everything logged within carries the request id.
+pub fn make_span<B>(req: &Request<B>) -> Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        request_id,
+    );
+
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    span.set_parent(parent);
+
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::{Body, Bytes},
+        http::StatusCode,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+    use tower_http::trace::TraceLayer;
+
+    use super::*;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_spans_to_collector() -> anyhow::Result<()> {
+        // stands in for the collector, passing on every export it receives
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| async move {
+                let _ = tx.send(body);
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        let config = config::Tracing {
+            otlp_endpoint: Some(format!("http://{addr}").parse()?),
+            service_name: "aoc-web-test".into(),
+            ..Default::default()
+        };
+        let provider = provider(&config)?;
+        let _guard = tracing_subscriber::registry()
+            .with(layer(&provider))
+            .set_default();
+
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(TraceLayer::new_for_http().make_span_with(make_span));
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header("traceparent", format!("00-{trace_id}-00f067aa0ba902b7-01"))
+                    .header(REQUEST_ID_HEADER, "abc-123")
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        // the span lasts as long as the response body
+        drop(response);
+
+        tokio::task::spawn_blocking(move || provider.force_flush()).await?;
+
+        let export = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await?
+            .context("collector went away")?;
+
+        // the export is protobuf encoded, but ids and strings appear verbatim
+        let trace_id = (0..trace_id.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&trace_id[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert!(contains(&export, &trace_id));
+        assert!(contains(&export, b"aoc-web-test"));
+        assert!(contains(&export, b"request"));
+        assert!(contains(&export, b"abc-123"));
+
+        Ok(())
+    }
+}